@@ -11,7 +11,7 @@ use serde::{Serialize,Deserialize};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::{JsFuture, future_to_promise};
-use web_sys::{Request, RequestInit, RequestMode, Response};
+use web_sys::{Request, RequestCredentials, RequestInit, RequestMode, Response, Url};
 
 type TermStats = BTreeMap<Token, TermStat>;
 type DocumentID = String;
@@ -31,15 +31,17 @@ const DOCUMENTS_FILE_EXTENSION : &str = "dcs";
 const DOCUMENT_STATS_FILE_EXTENSION : &str = "dst";
 const TERM_STATS_FILE_EXTENSION : &str = "tst";
 const SHARD_COUNT_FILE_NAME : &str = "shard_count";
+const TERM_SHARD_COUNT_FILE_NAME : &str = "term_shard_count";
+const DEFAULT_PATH_TEMPLATE : &str = "{name}/{shard}/{ext}";
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Hit {
     pub id: String,
     pub score: f64,
     pub source: Value,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SearchResult {
 	pub count: usize,
 	pub hits: Vec<Hit>,
@@ -106,17 +108,67 @@ impl TermStat {
     }
 }
 
+// Results of cached searches, keyed by the analyzed query and the options.
+// Least recently used entries are dropped first once it is full.
+struct ResultCache {
+    entries: Vec<(String, f64, SearchResult)>, // Key, time added in milliseconds and result, most recently used last
+    max_entries: usize,                        // 0 turns the cache off
+    ttl: f64,                                  // Milliseconds an entry is returned for after being added
+}
+
+impl ResultCache {
+    fn new() -> ResultCache {
+        ResultCache {
+            entries: Vec::new(),
+            max_entries: 0,
+            ttl: 0.0,
+        }
+    }
+
+    fn get(&mut self, key: &str, now: f64) -> Option<SearchResult> {
+        let ttl = self.ttl;
+        self.entries.retain(|(_, added, _)| now - added < ttl);
+        let position = self.entries.iter().position(|(k, _, _)| k == key)?;
+        let entry = self.entries.remove(position);
+        let result = entry.2.clone();
+        self.entries.push(entry);
+        Some(result)
+    }
+
+    fn insert(&mut self, key: String, now: f64, result: SearchResult) {
+        if self.max_entries == 0 {
+            return;
+        }
+        self.entries.retain(|(k, _, _)| *k != key);
+        if self.entries.len() >= self.max_entries {
+            self.entries.drain(..=self.entries.len() - self.max_entries);
+        }
+        self.entries.push((key, now, result));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
 #[wasm_bindgen]
 pub struct Index {
     name: String,
     documents: BTreeMap<DocumentID, Value>,
     document_stats: BTreeMap<DocumentID, DocumentStat>,
     term_stats: TermStats,
-    shard_count: usize,
+    shard_count: usize,      // Shards of the documents and document stats
+    term_shard_count: usize, // Shards of the term stats, the same as shard_count unless given
     loaded_documents_shards: BTreeMap<usize, bool>,
     loaded_document_stats_shards: BTreeMap<usize, bool>,
     loaded_term_stats_shards: BTreeMap<usize, bool>,
     base_url: String,
+    path_template: String,
+    request_mode: RequestMode,
+    request_credentials: RequestCredentials,
+    headers: BTreeMap<String, String>,
+    result_cache: ResultCache,
+    fetch_count: usize, // Requests sent for shards and metadata files
 }
 
 #[wasm_bindgen]
@@ -144,6 +196,66 @@ impl IndexHandle {
         })
     }
 
+    /// Points the index at a different origin. Cached shards are dropped so
+    /// later fetches hit the new origin; the shard count is kept, so call
+    /// `load` again if the index at the new origin differs.
+    #[wasm_bindgen(method)]
+    pub fn set_base_url(&self, base_url: String) {
+        self.index.borrow_mut().set_base_url(base_url);
+    }
+
+    /// Sets the layout of the shard files, e.g. `{name}/shard-{shard}.{ext}`.
+    /// Defaults to `{name}/{shard}/{ext}`.
+    #[wasm_bindgen(method)]
+    pub fn set_path_template(&self, path_template: String) {
+        let mut index = self.index.borrow_mut();
+        index.path_template = path_template;
+        index.result_cache.clear();
+    }
+
+    /// Sets the mode of the shard fetch requests. Defaults to `cors`.
+    #[wasm_bindgen(method)]
+    pub fn set_request_mode(&self, mode: RequestMode) {
+        let mut index = self.index.borrow_mut();
+        index.request_mode = mode;
+        index.result_cache.clear();
+    }
+
+    /// Sets the credentials mode of the shard fetch requests. Defaults to `same-origin`.
+    #[wasm_bindgen(method)]
+    pub fn set_request_credentials(&self, credentials: RequestCredentials) {
+        let mut index = self.index.borrow_mut();
+        index.request_credentials = credentials;
+        index.result_cache.clear();
+    }
+
+    /// Adds a header, e.g. `Authorization`, to every shard fetch request. It is
+    /// only sent to the origin of the base URL.
+    #[wasm_bindgen(method)]
+    pub fn set_header(&self, name: String, value: String) {
+        let mut index = self.index.borrow_mut();
+        index.headers.insert(name, value);
+        index.result_cache.clear();
+    }
+
+    #[wasm_bindgen(method)]
+    pub fn remove_header(&self, name: String) {
+        let mut index = self.index.borrow_mut();
+        index.headers.remove(&name);
+        index.result_cache.clear();
+    }
+
+    /// Keeps the results of up to `max_entries` searches for `ttl` milliseconds
+    /// so repeating a search does not fetch or score again. Searches with
+    /// `use_cache` off skip it. Off (`max_entries` 0) by default.
+    #[wasm_bindgen(method)]
+    pub fn set_result_cache(&self, max_entries: usize, ttl: f64) {
+        let mut index = self.index.borrow_mut();
+        index.result_cache.clear();
+        index.result_cache.max_entries = max_entries;
+        index.result_cache.ttl = ttl;
+    }
+
     #[wasm_bindgen(method)]
     pub fn search(&self, query: String) -> Promise {
         let index = self.index.clone();
@@ -171,15 +283,71 @@ impl Index {
             document_stats: BTreeMap::new(),
             term_stats: TermStats::new(),
             shard_count: 0,
+            term_shard_count: 0,
             loaded_documents_shards: BTreeMap::new(),
             loaded_document_stats_shards: BTreeMap::new(),
             loaded_term_stats_shards: BTreeMap::new(),
             base_url,
+            path_template: DEFAULT_PATH_TEMPLATE.to_string(),
+            request_mode: RequestMode::Cors,
+            request_credentials: RequestCredentials::SameOrigin,
+            headers: BTreeMap::new(),
+            result_cache: ResultCache::new(),
+            fetch_count: 0,
+        }
+    }
+
+    // Creates an empty index sharing this index's configuration
+    fn without_cache(&self) -> Index {
+        let mut index = Index::new(self.name.clone(), self.base_url.clone());
+        index.shard_count = self.shard_count;
+        index.term_shard_count = self.term_shard_count;
+        index.path_template = self.path_template.clone();
+        index.request_mode = self.request_mode;
+        index.request_credentials = self.request_credentials;
+        index.headers = self.headers.clone();
+        index
+    }
+
+    fn shard_url(&self, shard_id: ShardID, extension: &str) -> String {
+        let path = self.path_template
+            .replace("{name}", &self.name)
+            .replace("{shard}", &shard_id.to_string())
+            .replace("{ext}", extension);
+        format!("{}/{}", &self.base_url, path)
+    }
+
+    fn request(&self, url: &str) -> Result<Request, JsValue> {
+        let mut opts = RequestInit::new();
+        opts.method("GET");
+        opts.mode(self.request_mode);
+        opts.credentials(self.request_credentials);
+
+        let request = Request::new_with_str_and_init(url, &opts)?;
+        // Headers may carry credentials so only send them to the configured origin
+        let url_origin = origin(url);
+        if url_origin.is_some() && url_origin == origin(&self.base_url) {
+            for (name, value) in &self.headers {
+                request.headers().set(name, value)?;
+            }
         }
+        Ok(request)
+    }
+
+    fn set_base_url(&mut self, base_url: String) {
+        self.base_url = base_url;
+        self.documents.clear();
+        self.document_stats.clear();
+        self.term_stats.clear();
+        self.loaded_documents_shards.clear();
+        self.loaded_document_stats_shards.clear();
+        self.loaded_term_stats_shards.clear();
+        self.result_cache.clear();
     }
 
     pub async fn load(this: Rc<RefCell<Index>>) -> Result<Rc<RefCell<Index>>, JsValue> {
         Index::load_shard_count(this.clone()).await?;
+        Index::load_term_shard_count(this.clone()).await?;
         Ok(this)
     }
 
@@ -188,34 +356,69 @@ impl Index {
         Ok(JsValue::from_serde(&result).unwrap())
     }
 
+    fn metadata_url(&self, file_name: &str) -> String {
+        format!("{}/{}/{}", &self.base_url, &self.name, file_name)
+    }
+
     async fn load_shard_count(this: Rc<RefCell<Index>>) -> Result<JsValue, JsValue> {
-        let mut opts = RequestInit::new();
-        opts.method("GET");
-        opts.mode(RequestMode::Cors);
-    
-        let name = this.borrow().name.clone();
-        let base_url = this.borrow().base_url.clone();
-        let url = format!("{}/{}/{}", &base_url, &name, SHARD_COUNT_FILE_NAME);
-        let request = Request::new_with_str_and_init(&url, &opts)?;
-        let window = web_sys::window().unwrap();
-        let response_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-        assert!(response_value.is_instance_of::<Response>());
-
-        let response: Response = response_value.dyn_into().unwrap();
+        let url = this.borrow().metadata_url(SHARD_COUNT_FILE_NAME);
+        let response = fetch(this.clone(), &url).await?;
         Index::load_shard_count_from_response(this.clone(), response).await?;
         Ok(JsValue::NULL)
     }
 
     async fn load_shard_count_from_response(this: Rc<RefCell<Index>>, response: Response) -> Result<JsValue, JsValue> {
         let text = JsFuture::from(response.text().unwrap()).await?.as_string().unwrap();
-        let n = text.parse::<usize>().unwrap();
+        let n = text.trim().parse::<usize>().unwrap();
         this.borrow_mut().shard_count = n;
         Ok(JsValue::NULL)
     }
+
+    // The term_shard_count file is optional, without it the term stats are
+    // split into as many shards as the documents
+    async fn load_term_shard_count(this: Rc<RefCell<Index>>) -> Result<JsValue, JsValue> {
+        let url = this.borrow().metadata_url(TERM_SHARD_COUNT_FILE_NAME);
+        let response = fetch(this.clone(), &url).await?;
+        Index::load_term_shard_count_from_response(this.clone(), response).await?;
+        Ok(JsValue::NULL)
+    }
+
+    async fn load_term_shard_count_from_response(this: Rc<RefCell<Index>>, response: Response) -> Result<JsValue, JsValue> {
+        let n = if response.ok() {
+            let text = JsFuture::from(response.text().unwrap()).await?.as_string().unwrap();
+            text.trim().parse::<usize>().map_err(|_| JsValue::from_str("invalid term_shard_count file"))?
+        } else {
+            this.borrow().shard_count
+        };
+        this.borrow_mut().term_shard_count = n;
+        Ok(JsValue::NULL)
+    }
+}
+
+// The origin of `url`, resolved against the page's URL if it is relative.
+// None for an invalid URL or an opaque origin, e.g. of a `data:` URL.
+fn origin(url: &str) -> Option<String> {
+    let location = web_sys::window().and_then(|window| window.location().href().ok());
+    let url = match location {
+        Some(location) => Url::new_with_base(url, &location),
+        None => Url::new(url),
+    };
+    url.ok().map(|url| url.origin()).filter(|origin| origin != "null")
+}
+
+async fn fetch(this: Rc<RefCell<Index>>, url: &str) -> Result<Response, JsValue> {
+    let request = this.borrow().request(url)?;
+    this.borrow_mut().fetch_count += 1;
+    let window = web_sys::window().unwrap();
+    let response_value = JsFuture::from(window.fetch_with_request(&request)).await?;
+    assert!(response_value.is_instance_of::<Response>());
+
+    let response: Response = response_value.dyn_into().unwrap();
+    Ok(response)
 }
 
 fn analyze(s: &str) -> Vec<String> {
-    s.split(&[',', '、', '　', ' '][..])
+    s.split(|c: char| [',', '、', '　', ' '].contains(&c) || c.is_whitespace())
         .map(|s| s.to_lowercase())
         .map(|s| s.replace(PUNCTUATIONS, ""))
         .filter(|s| !STOP_WORDS.contains(&s.as_str()))
@@ -224,21 +427,24 @@ fn analyze(s: &str) -> Vec<String> {
 
 async fn search_with_options(this: Rc<RefCell<Index>>, query: &str, opts: SearchOptions) -> Result<SearchResult, JsValue> {
     if !opts.use_cache {
-        let name = this.borrow().name.clone();
-        let base_url = this.borrow().base_url.clone();
-        let shard_count = this.borrow().shard_count;
-        let mut index = Index::new(name, base_url);
-        index.shard_count = shard_count;
+        let index = this.borrow().without_cache();
         return do_search_with_options(Rc::new(RefCell::new(index)), query, opts).await
     }
-    return do_search_with_options(this, query, opts).await
+
+    let key = format!("{}\0{}\0{}", analyze(query).join(" "), opts.size, opts.from);
+    if let Some(result) = this.borrow_mut().result_cache.get(&key, js_sys::Date::now()) {
+        return Ok(result);
+    }
+    let result = do_search_with_options(this.clone(), query, opts).await?;
+    this.borrow_mut().result_cache.insert(key, js_sys::Date::now(), result.clone());
+    Ok(result)
 }
 
 async fn do_search_with_options(this: Rc<RefCell<Index>>, query: &str, opts: SearchOptions) -> Result<SearchResult, JsValue> {
     let tmp = analyze(query);
     let tokens: Vec<&str> = tmp.iter().map(String::as_str).collect();
     for token in &tokens {
-        let shard_id = calculate_shard_id(this.clone(), token);
+        let shard_id = calculate_term_shard_id(this.clone(), token);
         load_term_stats_from_shard(this.clone(), shard_id).await?;
     }
     let matched_document_ids = find_documents(this.clone(), &tokens)?;
@@ -250,7 +456,9 @@ async fn do_search_with_options(this: Rc<RefCell<Index>>, query: &str, opts: Sea
 }
 
 fn find_documents<'a>(this: Rc<RefCell<Index>>, tokens: &[&str]) -> Result<Vec<String>, JsValue> {
-    let mut document_ids_set = HashSet::new();
+    // None until the first term found in the index, an empty set after it
+    // means nothing matches every term
+    let mut document_ids_set: Option<HashSet<String>> = None;
 
     for token in tokens {
         let term_stat = if let Some(term_stat) = this.borrow().term_stats.get(*token) {
@@ -260,22 +468,21 @@ fn find_documents<'a>(this: Rc<RefCell<Index>>, tokens: &[&str]) -> Result<Vec<S
         };
 
         let ids: Vec<String> = term_stat.document_ids;
-        if document_ids_set.len() == 0 {
-            document_ids_set = ids.iter().cloned().collect();
-        } else if document_ids_set.len() == 1 {
-            break;
-        } else {
-            let mut new_ids_set = HashSet::new();
-            for id in ids {
-                if let Some(x) = document_ids_set.take(&id) {
-                    new_ids_set.insert(x);
+        document_ids_set = Some(match document_ids_set {
+            None => ids.into_iter().collect(),
+            Some(mut document_ids_set) => {
+                let mut new_ids_set = HashSet::new();
+                for id in ids {
+                    if let Some(x) = document_ids_set.take(&id) {
+                        new_ids_set.insert(x);
+                    }
                 }
-            }
-            document_ids_set = new_ids_set;
-        }
+                new_ids_set
+            },
+        });
     }
 
-    let document_ids = document_ids_set.into_iter().collect();
+    let document_ids = document_ids_set.unwrap_or_default().into_iter().collect();
 
     Ok(document_ids)
 }
@@ -288,21 +495,20 @@ async fn sort_documents<'a>(this: Rc<RefCell<Index>>, document_ids: Vec<String>,
         document_id_scores.push((document_id, score));
     }
 
-    document_id_scores.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+    // Highest score first, ties broken by document ID so the order is stable across runs
+    document_id_scores.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
     let (document_ids, scores) = document_id_scores.into_iter().unzip();
     Ok((document_ids, scores))
 }
 
 async fn fetch_hits(this: Rc<RefCell<Index>>, document_ids: Vec<String>, scores: Vec<f64>, size: usize, from: usize) -> Result<Vec<Hit>, JsValue> {
-    let mut n = document_ids.len();
     let mut hits = Vec::new();
 
-    if size == 0 || from >= n {
+    if size == 0 || from >= document_ids.len() {
         return Ok(hits);
     }
-    if n > size {
-        n = size;
-    }
+    // The last page may hold fewer than `size` hits
+    let n = size.min(document_ids.len() - from);
 
     for (id, score) in document_ids.iter().cloned().zip(scores).skip(from).take(n) {
         let source = fetch_document(this.clone(), &id).await?;
@@ -332,19 +538,8 @@ async fn load_documents_from_shard(this: Rc<RefCell<Index>>, shard_id: ShardID)
         return Ok(())
     }
 
-    let mut opts = RequestInit::new();
-    opts.method("GET");
-    opts.mode(RequestMode::Cors);
-
-    let name = this.borrow().name.clone();
-    let base_url = this.borrow().base_url.clone();
-    let url = format!("{}/{}/{}/{}", &base_url, &name, &shard_id, DOCUMENTS_FILE_EXTENSION);
-    let request = Request::new_with_str_and_init(&url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let response_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    assert!(response_value.is_instance_of::<Response>());
-
-    let response: Response = response_value.dyn_into().unwrap();
+    let url = this.borrow().shard_url(shard_id, DOCUMENTS_FILE_EXTENSION);
+    let response = fetch(this.clone(), &url).await?;
     load_documents_from_response(this.clone(), response).await?;
     this.borrow_mut().loaded_documents_shards.insert(shard_id as usize, true);
 
@@ -381,16 +576,26 @@ fn document_from_record(headers: &StringRecord, record: &StringRecord) -> Value
 }
 
 fn calculate_shard_id(this: Rc<RefCell<Index>>, s: &str) -> ShardID {
+    let shard_count = this.borrow().shard_count as u32;
+    shard_id(s, shard_count)
+}
+
+fn calculate_term_shard_id(this: Rc<RefCell<Index>>, token: &str) -> ShardID {
+    let term_shard_count = this.borrow().term_shard_count as u32;
+    shard_id(token, term_shard_count)
+}
+
+// Must route like the folder-rs crate that wrote the index
+fn shard_id(s: &str, shard_count: u32) -> ShardID {
     const Q: ShardID = 123456789;
 
-    let mut result = 0;
+    let mut result: ShardID = 0;
     for c in s.chars() {
-        result += Q + (c as u32) * (c as u32);
+        result = result.wrapping_add(Q.wrapping_add((c as u32).wrapping_mul(c as u32)));
     }
-    result *= Q;
+    result = result.wrapping_mul(Q);
 
-    let shard_id = result % this.borrow().shard_count as u32;
-    shard_id
+    result % shard_count
 }
 
 async fn load_document_stats_from_shard(this: Rc<RefCell<Index>>, shard_id: ShardID) -> Result<(), JsValue> {
@@ -398,19 +603,8 @@ async fn load_document_stats_from_shard(this: Rc<RefCell<Index>>, shard_id: Shar
         return Ok(())
     }
 
-    let mut opts = RequestInit::new();
-    opts.method("GET");
-    opts.mode(RequestMode::Cors);
-
-    let name = this.borrow().name.clone();
-    let base_url = this.borrow().base_url.clone();
-    let url = format!("{}/{}/{}/{}", &base_url, &name, &shard_id, DOCUMENT_STATS_FILE_EXTENSION);
-    let request = Request::new_with_str_and_init(&url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let response_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    assert!(response_value.is_instance_of::<Response>());
-
-    let response: Response = response_value.dyn_into().unwrap();
+    let url = this.borrow().shard_url(shard_id, DOCUMENT_STATS_FILE_EXTENSION);
+    let response = fetch(this.clone(), &url).await?;
     load_document_stats_from_response(this.clone(), response).await?;
     this.borrow_mut().loaded_document_stats_shards.insert(shard_id as usize, true);
 
@@ -424,16 +618,18 @@ async fn load_document_stats_from_response(this: Rc<RefCell<Index>>, response: R
     for result in csvr.records() {
         let record = result.unwrap();
         let document_id = record.get(0).unwrap();
-        let tfs: Vec<&str> = record.get(1).unwrap().split(' ').collect();
+
+        // Documents without any indexed terms have an empty row
+        if !this.borrow().document_stats.contains_key(document_id) {
+            this.borrow_mut().document_stats.insert(document_id.to_string(), DocumentStat::new());
+        }
+
+        let tfs: Vec<&str> = record.get(1).unwrap().split(' ').filter(|v| !v.is_empty()).collect();
         for v in tfs {
             let vv: Vec<&str> = v.split(':').collect();
             let term = vv.get(0).unwrap();
             let frequency = vv.get(1).unwrap();
 
-            if !this.borrow().document_stats.contains_key(document_id) {
-                this.borrow_mut().document_stats.insert(document_id.to_string(), DocumentStat::new());
-            }
-
             if let Some(document_stat) = this.borrow_mut().document_stats.get_mut(document_id) {
                 let frequency: usize = frequency.parse().unwrap();
                 document_stat.get_term_frequency_mut().insert(term.to_string(), frequency);
@@ -462,7 +658,7 @@ pub async fn fetch_term_stat(this: Rc<RefCell<Index>>, token: &str) -> Result<Op
         let term_stat = term_stats.get(token).unwrap();
         Ok(Some(term_stat.clone()))
     } else {
-        let shard_id = calculate_shard_id(this.clone(), token);
+        let shard_id = calculate_term_shard_id(this.clone(), token);
         load_term_stats_from_shard(this.clone(), shard_id).await?;
 
         if let Some(term_stat) = this.borrow().term_stats.get(token) {
@@ -478,21 +674,10 @@ async fn load_term_stats_from_shard(this: Rc<RefCell<Index>>, shard_id: ShardID)
         return Ok(())
     }
 
-    let mut opts = RequestInit::new();
-    opts.method("GET");
-    opts.mode(RequestMode::Cors);
-
-    let name = this.borrow().name.clone();
-    let base_url = this.borrow().base_url.clone();
-    let url = format!("{}/{}/{}/{}", &base_url, &name, &shard_id, TERM_STATS_FILE_EXTENSION);
-    let request = Request::new_with_str_and_init(&url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let response_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    assert!(response_value.is_instance_of::<Response>());
-
-    let response: Response = response_value.dyn_into().unwrap();
+    let url = this.borrow().shard_url(shard_id, TERM_STATS_FILE_EXTENSION);
+    let response = fetch(this.clone(), &url).await?;
     load_term_stats_from_response(this.clone(), response).await?;
-    this.borrow_mut().loaded_documents_shards.insert(shard_id as usize, true);
+    this.borrow_mut().loaded_term_stats_shards.insert(shard_id as usize, true);
 
     Ok(())
 }
@@ -557,4 +742,163 @@ fn inverse_document_frequency(this: Rc<RefCell<Index>>, token: &str) -> Result<f
 fn document_frequency(this: Rc<RefCell<Index>>, token: &str) -> f64 {
     let n = this.borrow().term_stats[token].document_ids.len() as u32;
     n as f64
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::wasm_bindgen_test;
+    use web_sys::ResponseInit;
+
+    #[wasm_bindgen_test]
+    fn request_sends_the_configured_headers_to_the_base_origin() {
+        let handle = IndexHandle::new("index".to_string(), "https://example.com/search".to_string());
+        let authorization = |url: &str| {
+            let request = handle.index.borrow().request(url).unwrap();
+            request.headers().get("Authorization").unwrap()
+        };
+        let shard_url = handle.index.borrow().shard_url(0, DOCUMENTS_FILE_EXTENSION);
+        assert_eq!(shard_url, "https://example.com/search/index/0/dcs");
+
+        handle.set_header("Authorization".to_string(), "Bearer token".to_string());
+        assert_eq!(authorization(&shard_url), Some("Bearer token".to_string()));
+        assert_eq!(authorization("https://example.com:8443/search/index/0/dcs"), None);
+        assert_eq!(authorization("https://example.com.evil.test/search/index/0/dcs"), None);
+        assert_eq!(authorization("http://example.com/search/index/0/dcs"), None);
+
+        handle.remove_header("Authorization".to_string());
+        assert_eq!(authorization(&shard_url), None);
+    }
+
+    #[wasm_bindgen_test]
+    async fn term_shard_count_routes_the_terms() {
+        let index = Rc::new(RefCell::new(Index::new("index".to_string(), "https://example.com".to_string())));
+        index.borrow_mut().shard_count = 4;
+
+        let response = Response::new_with_opt_str(Some("2\n")).unwrap();
+        Index::load_term_shard_count_from_response(index.clone(), response).await.unwrap();
+        assert_eq!(index.borrow().term_shard_count, 2);
+        assert_eq!(calculate_shard_id(index.clone(), "moon"), 3);
+        assert_eq!(calculate_term_shard_id(index.clone(), "moon"), 1);
+
+        // Without the file the terms are split like the documents
+        let init = ResponseInit::new();
+        init.set_status(404);
+        let response = Response::new_with_opt_str_and_init(None, &init).unwrap();
+        Index::load_term_shard_count_from_response(index.clone(), response).await.unwrap();
+        assert_eq!(calculate_term_shard_id(index.clone(), "moon"), 3);
+    }
+
+    async fn load_shards(index: Rc<RefCell<Index>>, dcs: &str, dst: &str, tst: &str) {
+        load_documents_from_response(index.clone(), Response::new_with_opt_str(Some(dcs)).unwrap()).await.unwrap();
+        load_document_stats_from_response(index.clone(), Response::new_with_opt_str(Some(dst)).unwrap()).await.unwrap();
+        load_term_stats_from_response(index.clone(), Response::new_with_opt_str(Some(tst)).unwrap()).await.unwrap();
+        let mut index = index.borrow_mut();
+        index.loaded_documents_shards.insert(0, true);
+        index.loaded_document_stats_shards.insert(0, true);
+        index.loaded_term_stats_shards.insert(0, true);
+    }
+
+    #[wasm_bindgen_test]
+    async fn repeated_search_is_served_from_the_cache() {
+        let handle = IndexHandle::new("index".to_string(), "https://example.com".to_string());
+        handle.set_result_cache(10, 60_000.0);
+        handle.index.borrow_mut().shard_count = 1;
+        handle.index.borrow_mut().term_shard_count = 1;
+        load_shards(handle.index.clone(), "id,body\na,Full moon\nb,New moon\nc,Sun\n", "id,terms\na,full:1 moon:1\nb,new:1 moon:1\nc,sun:1\n", "term,ids\nfull,a\nmoon,a b\nnew,b\nsun,c\n").await;
+        let opts = || SearchOptions{ use_cache: true, ..SearchOptions::default() };
+
+        let first = search_with_options(handle.index.clone(), "full moon", opts()).await.unwrap();
+        assert_eq!(first.hits.iter().map(|hit| hit.id.as_str()).collect::<Vec<_>>(), ["a"]);
+        assert_eq!(handle.index.borrow().fetch_count, 0);
+
+        // Searching again for real would have to fetch the shards
+        {
+            let mut index = handle.index.borrow_mut();
+            index.documents.clear();
+            index.document_stats.clear();
+            index.term_stats.clear();
+            index.loaded_documents_shards.clear();
+            index.loaded_document_stats_shards.clear();
+            index.loaded_term_stats_shards.clear();
+        }
+        let second = search_with_options(handle.index.clone(), "Full Moon", opts()).await.unwrap();
+        assert_eq!(serde_json::to_string(&second).unwrap(), serde_json::to_string(&first).unwrap());
+        assert_eq!(handle.index.borrow().fetch_count, 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn result_cache_drops_entries_after_their_ttl() {
+        let mut cache = ResultCache::new();
+        cache.max_entries = 10;
+        cache.ttl = 1_000.0;
+        cache.insert("moon".to_string(), 0.0, SearchResult{ hits: Vec::new(), count: 0 });
+
+        assert!(cache.get("moon", 999.0).is_some());
+        assert!(cache.get("moon", 1_000.0).is_none());
+        assert!(cache.entries.is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    async fn base_url_change_drops_the_loaded_shards() {
+        let handle = IndexHandle::new("index".to_string(), "https://a.example.com".to_string());
+        handle.index.borrow_mut().shard_count = 1;
+        handle.index.borrow_mut().term_shard_count = 1;
+        load_shards(handle.index.clone(), "id,body\na,Full moon\n", "id,terms\na,full:1 moon:1\n", "term,ids\nfull,a\nmoon,a\n").await;
+
+        handle.set_base_url("https://b.example.com".to_string());
+        let index = handle.index.borrow();
+        assert!(index.documents.is_empty() && index.document_stats.is_empty() && index.term_stats.is_empty());
+        assert!(index.loaded_documents_shards.is_empty() && index.loaded_document_stats_shards.is_empty() && index.loaded_term_stats_shards.is_empty());
+        let request = index.request(&index.shard_url(0, TERM_STATS_FILE_EXTENSION)).unwrap();
+        assert_eq!(request.url(), "https://b.example.com/index/0/tst");
+    }
+
+    #[wasm_bindgen_test]
+    fn request_uses_the_configured_mode_and_credentials() {
+        let handle = IndexHandle::new("index".to_string(), "https://example.com".to_string());
+        let request = |handle: &IndexHandle| {
+            let index = handle.index.borrow();
+            index.request(&index.shard_url(0, DOCUMENTS_FILE_EXTENSION)).unwrap()
+        };
+        assert_eq!(request(&handle).mode(), RequestMode::Cors);
+        assert_eq!(request(&handle).credentials(), RequestCredentials::SameOrigin);
+
+        handle.set_request_mode(RequestMode::NoCors);
+        handle.set_request_credentials(RequestCredentials::Include);
+        assert_eq!(request(&handle).mode(), RequestMode::NoCors);
+        assert_eq!(request(&handle).credentials(), RequestCredentials::Include);
+    }
+
+    #[wasm_bindgen_test]
+    fn request_settings_clear_the_result_cache() {
+        let handle = IndexHandle::new("index".to_string(), "https://example.com".to_string());
+        handle.set_result_cache(10, 60_000.0);
+        let cached = |handle: &IndexHandle| {
+            let result = SearchResult{ hits: Vec::new(), count: 0 };
+            handle.index.borrow_mut().result_cache.insert("moon".to_string(), 0.0, result);
+        };
+        let is_cached = |handle: &IndexHandle| handle.index.borrow_mut().result_cache.get("moon", 1.0).is_some();
+
+        cached(&handle);
+        assert!(is_cached(&handle));
+        handle.set_path_template("{name}/shard-{shard}.{ext}".to_string());
+        assert!(!is_cached(&handle));
+
+        cached(&handle);
+        handle.set_request_mode(RequestMode::SameOrigin);
+        assert!(!is_cached(&handle));
+
+        cached(&handle);
+        handle.set_request_credentials(RequestCredentials::Include);
+        assert!(!is_cached(&handle));
+
+        cached(&handle);
+        handle.set_header("Authorization".to_string(), "Bearer token".to_string());
+        assert!(!is_cached(&handle));
+
+        cached(&handle);
+        handle.remove_header("Authorization".to_string());
+        assert!(!is_cached(&handle));
+    }
+}
@@ -0,0 +1,317 @@
+use serde::Serialize;
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+// Split on these in addition to any Unicode whitespace (e.g. U+00A0, U+2009)
+const SEPARATORS: &[char] = &[',', '、', '　', ' '];
+const PUNCTUATIONS: &[char]= &['!','"','#','$','%','&','(',')','*','+',',','-','.','/',':',';','<','=','>','?','@','[','\\',']','^','_','`','{','|','}','~'];
+const ENGLISH_STOP_WORDS: &[&str] = &[
+	"a", "and", "are", "as", "at", "be", "but", "by", "for",
+	"if", "in", "into", "is", "it", "no", "not", "of", "on",
+	"or", "s", "such", "t", "that", "the", "their", "then",
+	"there", "these", "they", "this", "to", "was", "will",
+	"with", "www",
+];
+const FRENCH_STOP_WORDS: &[&str] = &[
+	"au", "aux", "avec", "c", "ce", "ces", "d", "dans", "de",
+	"des", "du", "elle", "en", "est", "et", "il", "ils", "j",
+	"je", "l", "la", "le", "les", "leur", "lui", "m", "ma",
+	"mais", "me", "mes", "n", "ne", "nos", "notre", "nous",
+	"on", "ou", "où", "par", "pas", "pour", "qu", "que", "qui",
+	"s", "sa", "se", "ses", "son", "sont", "sur", "t", "ta",
+	"te", "tes", "un", "une", "vos", "votre", "vous", "y", "à",
+];
+const GERMAN_STOP_WORDS: &[&str] = &[
+	"aber", "als", "am", "an", "auch", "auf", "aus", "bei",
+	"bin", "bis", "das", "dass", "dem", "den", "der", "des",
+	"die", "du", "ein", "eine", "einem", "einen", "einer",
+	"eines", "er", "es", "für", "hat", "ich", "ihr", "im",
+	"in", "ist", "mit", "nach", "nicht", "noch", "nur", "oder",
+	"sie", "sind", "so", "um", "und", "uns", "von", "vor",
+	"war", "was", "wie", "wir", "zu", "zum", "zur",
+];
+
+/// Language whose stop words the analyzer removes. Only the stop words
+/// differ between languages: no stemming is done, so e.g. "lunes" and "lune"
+/// stay distinct terms. An index has a single analyzer, so every field is
+/// analyzed in the same language.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Language {
+    #[default]
+    English,
+    French,
+    German,
+}
+
+impl Language {
+    pub fn stop_words(&self) -> &'static [&'static str] {
+        match self {
+            Language::English => ENGLISH_STOP_WORDS,
+            Language::French => FRENCH_STOP_WORDS,
+            Language::German => GERMAN_STOP_WORDS,
+        }
+    }
+}
+
+/// A single step of the analysis pipeline. A filter receives one token and
+/// returns the tokens that replace it, so it can drop (empty), rewrite (one)
+/// or expand (several) the token. Filters are `Send` and `Sync` so that an
+/// `Index` holding them can be moved to and shared between threads.
+pub trait TokenFilter: Send + Sync {
+    fn filter(&self, token: String) -> Vec<String>;
+}
+
+pub struct LowercaseFilter;
+
+impl TokenFilter for LowercaseFilter {
+    fn filter(&self, token: String) -> Vec<String> {
+        vec![token.to_lowercase()]
+    }
+}
+
+pub struct PunctuationFilter;
+
+impl TokenFilter for PunctuationFilter {
+    fn filter(&self, token: String) -> Vec<String> {
+        vec![token.replace(PUNCTUATIONS, "")]
+    }
+}
+
+/// Removes English stop words.
+pub struct StopWordFilter;
+
+impl TokenFilter for StopWordFilter {
+    fn filter(&self, token: String) -> Vec<String> {
+        LanguageStopWordFilter::new(Language::English).filter(token)
+    }
+}
+
+/// Removes the stop words of the given language.
+pub struct LanguageStopWordFilter {
+    language: Language,
+}
+
+impl LanguageStopWordFilter {
+    pub fn new(language: Language) -> Self {
+        Self { language }
+    }
+}
+
+impl TokenFilter for LanguageStopWordFilter {
+    fn filter(&self, token: String) -> Vec<String> {
+        if self.language.stop_words().contains(&token.as_str()) {
+            return Vec::new();
+        }
+        vec![token]
+    }
+}
+
+/// Strips diacritics so that e.g. "café" becomes "cafe".
+pub struct AsciiFoldingFilter;
+
+impl TokenFilter for AsciiFoldingFilter {
+    fn filter(&self, token: String) -> Vec<String> {
+        vec![token.nfd().filter(|c| !is_combining_mark(*c)).collect()]
+    }
+}
+
+/// Like `AsciiFoldingFilter` but keeps the original token too, so "café"
+/// becomes "café" and "cafe". Filters only change text analyzed after they
+/// are added, so call `Index::reindex` for the stored documents to be indexed
+/// under both forms. Every term of a query has to match, so an accented query
+/// then only finds documents with the accented form while an unaccented one
+/// finds both. Without reindexing an accented query also needs the folded
+/// term in the same document and may find nothing.
+pub struct PreservingAsciiFoldingFilter;
+
+impl TokenFilter for PreservingAsciiFoldingFilter {
+    fn filter(&self, token: String) -> Vec<String> {
+        let folded: String = token.nfd().filter(|c| !is_combining_mark(*c)).collect();
+        if folded == token {
+            return vec![token];
+        }
+        vec![token, folded]
+    }
+}
+
+/// Splits camelCase and snake_case identifiers into their lowercased parts,
+/// followed by the whole identifier lowercased, so "getUserName" becomes
+/// "get", "user", "name" and "getusername". It needs the original casing and
+/// underscores, so put it before the lowercase and punctuation filters.
+/// Filters only change text analyzed after they are added, so call
+/// `Index::reindex` for the stored documents to be split too. Until then the
+/// parts of a query identifier are matched against documents written whole,
+/// and any part that is a term of other documents rules them out.
+pub struct IdentifierSplitFilter;
+
+impl TokenFilter for IdentifierSplitFilter {
+    fn filter(&self, token: String) -> Vec<String> {
+        let chars: Vec<char> = token.chars().collect();
+        let mut parts = Vec::new();
+        let mut part = String::new();
+
+        for (i, c) in chars.iter().enumerate() {
+            if *c == '_' {
+                parts.push(std::mem::take(&mut part));
+                continue;
+            }
+            // A part starts at an uppercase letter following a lowercase one
+            // or a digit, or at the last capital of a run such as "HTTPServer"
+            let previous = if i > 0 { Some(chars[i - 1]) } else { None };
+            let next = chars.get(i + 1);
+            let starts_part = c.is_uppercase() && match previous {
+                Some(previous) if previous.is_lowercase() || previous.is_numeric() => true,
+                Some(previous) if previous.is_uppercase() => next.is_some_and(|next| next.is_lowercase()),
+                _ => false,
+            };
+            if starts_part {
+                parts.push(std::mem::take(&mut part));
+            }
+            part.push(*c);
+        }
+        parts.push(part);
+
+        let mut tokens: Vec<String> = parts.into_iter()
+            .filter(|part| !part.is_empty())
+            .map(|part| part.to_lowercase())
+            .collect();
+        if tokens.len() <= 1 {
+            return vec![token];
+        }
+        tokens.push(token.to_lowercase());
+        tokens
+    }
+}
+
+/// A token produced by the analyzer along with the span of the input it
+/// came from. Tokens a filter expands into share their source's span.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AnalyzedToken {
+    pub text: String,
+    pub start: usize,    // Byte offset of the span's first character
+    pub end: usize,      // Byte offset just past the span
+    pub position: usize, // Index of the span among all spans, gaps mark dropped tokens
+}
+
+/// Splits text into tokens and runs them through an ordered list of filters.
+/// The default filters are lowercase, punctuation removal and stop word
+/// removal, in that order.
+///
+/// With numeric tokens enabled, a comma followed by a group of exactly three
+/// digits is a thousands separator rather than a token separator ("1,000"
+/// becomes "1000"), and numbers such as "2024" or "3.14" skip the filters so
+/// their decimal point survives punctuation removal.
+pub struct Analyzer {
+    filters: Vec<Box<dyn TokenFilter>>,
+    numeric_tokens: bool,
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Self {
+            filters: vec![
+                Box::new(LowercaseFilter),
+                Box::new(PunctuationFilter),
+                Box::new(StopWordFilter),
+            ],
+            numeric_tokens: false,
+        }
+    }
+}
+
+impl Analyzer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The default filters with the stop words of `language` instead of English.
+    /// It applies to every field, there is no per-field language.
+    pub fn for_language(language: Language) -> Self {
+        Self::with_filters(vec![
+            Box::new(LowercaseFilter),
+            Box::new(PunctuationFilter),
+            Box::new(LanguageStopWordFilter::new(language)),
+        ])
+    }
+
+    pub fn with_filters(filters: Vec<Box<dyn TokenFilter>>) -> Self {
+        Self { filters, numeric_tokens: false }
+    }
+
+    pub fn get_filters_mut(&mut self) -> &mut Vec<Box<dyn TokenFilter>> {
+        &mut self.filters
+    }
+
+    pub fn set_numeric_tokens(&mut self, numeric_tokens: bool) {
+        self.numeric_tokens = numeric_tokens;
+    }
+
+    pub fn analyze(&self, s: &str) -> Vec<String> {
+        self.analyze_with_offsets(s).into_iter().map(|token| token.text).collect()
+    }
+
+    pub fn analyze_with_offsets(&self, s: &str) -> Vec<AnalyzedToken> {
+        let mut tokens: Vec<AnalyzedToken> = self.split(s).into_iter()
+            .enumerate()
+            .map(|(position, (start, end, text))| AnalyzedToken{ text, start, end, position })
+            .collect();
+
+        for filter in &self.filters {
+            tokens = tokens.into_iter().flat_map(|token| {
+                if self.numeric_tokens && is_number(&token.text) {
+                    return vec![token];
+                }
+                let AnalyzedToken{ text, start, end, position } = token;
+                filter.filter(text).into_iter()
+                    .map(|text| AnalyzedToken{ text, start, end, position })
+                    .collect()
+            }).collect();
+        }
+        tokens
+    }
+
+    // Splits the input into (start, end, text) spans
+    fn split(&self, s: &str) -> Vec<(usize, usize, String)> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut spans = Vec::new();
+        let mut start = 0;
+        let mut text = String::new();
+
+        for (i, (offset, c)) in s.char_indices().enumerate() {
+            if self.numeric_tokens && is_thousands_separator(&chars, i) {
+                continue;
+            }
+            if is_separator(c) {
+                spans.push((start, offset, std::mem::take(&mut text)));
+                start = offset + c.len_utf8();
+            } else {
+                text.push(c);
+            }
+        }
+        spans.push((start, s.len(), text));
+
+        spans
+    }
+}
+
+fn is_separator(c: char) -> bool {
+    SEPARATORS.contains(&c) || c.is_whitespace()
+}
+
+fn is_thousands_separator(chars: &[char], i: usize) -> bool {
+    chars[i] == ',' && i > 0 && chars[i - 1].is_ascii_digit() && is_digit_group(&chars[i + 1..])
+}
+
+fn is_digit_group(chars: &[char]) -> bool {
+    chars.len() >= 3
+        && chars[..3].iter().all(char::is_ascii_digit)
+        && !matches!(chars.get(3), Some(c) if c.is_ascii_digit())
+}
+
+fn is_number(token: &str) -> bool {
+    let is_digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+    match token.split_once('.') {
+        Some((integer, fraction)) => is_digits(integer) && is_digits(fraction),
+        None => is_digits(token),
+    }
+}
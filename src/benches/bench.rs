@@ -1,12 +1,182 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use folder_rs::Index;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use folder_rs::{Index, Intersection, SearchOptions, TermStat};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+
+const INDEX_NAME: &str = "index";
 
 fn criterion_benchmark(c: &mut Criterion) {
-    let mut index = Index::load("index").unwrap();
+    let mut index = Index::load(INDEX_NAME).unwrap();
     c.bench_function("search \"lunar new year\"", |b| b.iter(|| {
         index.search(black_box("lunar new year")).unwrap();
     }));
+
+    // The repeated token loads the same shard as the single one, so the two
+    // should take about as long
+    let mut group = c.benchmark_group("repeated token");
+    group.bench_function("search \"lunar\"", |b| b.iter(|| {
+        index.search(black_box("lunar")).unwrap();
+    }));
+    group.bench_function("search \"lunar lunar lunar lunar lunar\"", |b| b.iter(|| {
+        index.search(black_box("lunar lunar lunar lunar lunar")).unwrap();
+    }));
+    group.finish();
+}
+
+// Cold searches start from a freshly loaded index so every shard the query
+// needs is read and parsed, while warm searches run against an index with
+// all shards already in memory. Cold should be far slower than warm; if
+// both regress together the cost is in scoring rather than I/O.
+fn cold_warm_benchmark(c: &mut Criterion) {
+    c.bench_function("cold search \"lunar new year\"", |b| b.iter_batched(
+        || Index::load(INDEX_NAME).unwrap(),
+        |mut index| index.search(black_box("lunar new year")).unwrap(),
+        BatchSize::SmallInput,
+    ));
+
+    let mut index = Index::load(INDEX_NAME).unwrap();
+    index.warm().unwrap();
+    c.bench_function("warm search \"lunar new year\"", |b| b.iter(|| {
+        index.search(black_box("lunar new year")).unwrap();
+    }));
+}
+
+// Writes a single shard index where "common" is in every document, "half"
+// in every other one and "rare" in only a few, so the posting lists being
+// intersected are large and uneven.
+fn write_posting_lists_index(document_count: usize) -> String {
+    let index_name = std::env::temp_dir().join("folder-rs-bench-posting-lists");
+    let index_name = index_name.to_str().unwrap().to_string();
+    fs::create_dir_all(format!("{}/0", index_name)).unwrap();
+
+    let mut documents = String::from("id,body\n");
+    let mut document_stats = String::from("id,terms\n");
+    let (mut common, mut half, mut rare) = (Vec::new(), Vec::new(), Vec::new());
+    for id in 0..document_count {
+        let mut terms = vec!["common"];
+        common.push(id.to_string());
+        if id % 2 == 0 {
+            terms.push("half");
+            half.push(id.to_string());
+        }
+        if id % (document_count / 8) == 0 {
+            terms.push("rare");
+            rare.push(id.to_string());
+        }
+        writeln!(documents, "{},{}", id, terms.join(" ")).unwrap();
+        let frequencies: Vec<String> = terms.iter().map(|term| format!("{}:1", term)).collect();
+        writeln!(document_stats, "{},{}", id, frequencies.join(" ")).unwrap();
+    }
+
+    let term_stats = format!("term,ids\ncommon,{}\nhalf,{}\nrare,{}\n", common.join(" "), half.join(" "), rare.join(" "));
+    fs::write(format!("{}/shard_count", index_name), "1").unwrap();
+    fs::write(format!("{}/0/dcs", index_name), documents).unwrap();
+    fs::write(format!("{}/0/dst", index_name), document_stats).unwrap();
+    fs::write(format!("{}/0/tst", index_name), term_stats).unwrap();
+    index_name
+}
+
+fn intersection_benchmark(c: &mut Criterion) {
+    let index_name = write_posting_lists_index(100_000);
+    let mut index = Index::load(&index_name).unwrap();
+    index.warm().unwrap();
+
+    for (name, intersection) in &[("filter", Intersection::Filter), ("sorted merge", Intersection::SortedMerge)] {
+        c.bench_function(&format!("{} intersection \"common half rare\"", name), |b| b.iter(|| {
+            let opts = SearchOptions{ intersection: *intersection, ..SearchOptions::default() };
+            index.search_with_options(black_box("common half rare"), opts).unwrap();
+        }));
+    }
+}
+
+fn scoring_benchmark(c: &mut Criterion) {
+    let index_name = write_posting_lists_index(100_000);
+    let mut index = Index::load(&index_name).unwrap();
+    index.warm().unwrap();
+
+    // Parallel scoring only differs from serial with the rayon feature on
+    for (name, score, parallel_scoring) in &[("scored", true, false), ("parallel scored", true, true), ("unscored", false, false)] {
+        c.bench_function(&format!("{} search \"common\"", name), |b| b.iter(|| {
+            let opts = SearchOptions{ score: *score, parallel_scoring: *parallel_scoring, ..SearchOptions::default() };
+            index.search_with_options(black_box("common"), opts).unwrap();
+        }));
+    }
+}
+
+type TermFrequencies = BTreeMap<String, BTreeMap<String, usize>>;
+
+// The same data as `write_posting_lists_index`, as term stats and each
+// document's term frequencies
+fn posting_lists_stats(document_count: usize) -> (BTreeMap<String, TermStat>, TermFrequencies) {
+    let mut term_stats: BTreeMap<String, TermStat> = BTreeMap::new();
+    let mut document_stats = BTreeMap::new();
+    for id in 0..document_count {
+        let mut terms = vec!["common"];
+        if id % 2 == 0 {
+            terms.push("half");
+        }
+        if id % (document_count / 8) == 0 {
+            terms.push("rare");
+        }
+        let mut term_frequencies = BTreeMap::new();
+        for term in terms {
+            term_frequencies.insert(term.to_string(), 1);
+            term_stats.entry(term.to_string()).or_insert_with(TermStat::new).get_document_id_set_mut().insert(id.to_string());
+        }
+        document_stats.insert(id.to_string(), term_frequencies);
+    }
+    (term_stats, document_stats)
+}
+
+fn inverse_document_frequency(document_count: usize, term_stats: &BTreeMap<String, TermStat>, token: &str) -> f64 {
+    let document_frequency = term_stats.get(token).map_or(0, |term_stat| term_stat.get_document_id_set().len());
+    if document_frequency == 0 {
+        return 0.0;
+    }
+    (document_count as f64 / document_frequency as f64).log10().max(0.0)
+}
+
+fn term_frequency(document_stats: &TermFrequencies, document_id: &str, token: &str) -> f64 {
+    document_stats.get(document_id).and_then(|term_frequencies| term_frequencies.get(token)).map_or(0.0, |n| *n as f64)
+}
+
+// How documents were scored before: the matched set first, then every
+// document's score with each token's IDF worked out again
+fn score_two_pass(term_stats: &BTreeMap<String, TermStat>, document_stats: &TermFrequencies, document_ids: &[&str], tokens: &[&str]) -> Vec<f64> {
+    document_ids.iter()
+        .map(|document_id| tokens.iter()
+            .map(|token| term_frequency(document_stats, document_id, token) * inverse_document_frequency(document_stats.len(), term_stats, token))
+            .sum())
+        .collect()
+}
+
+// How `Index` scores now: each token's IDF once, then one pass over the
+// matched documents
+fn score_single_pass(term_stats: &BTreeMap<String, TermStat>, document_stats: &TermFrequencies, document_ids: &[&str], tokens: &[&str]) -> Vec<f64> {
+    let idfs: Vec<f64> = tokens.iter().map(|token| inverse_document_frequency(document_stats.len(), term_stats, token)).collect();
+    document_ids.iter()
+        .map(|document_id| tokens.iter().zip(&idfs)
+            .map(|(token, idf)| term_frequency(document_stats, document_id, token) * idf)
+            .sum())
+        .collect()
+}
+
+fn single_pass_scoring_benchmark(c: &mut Criterion) {
+    let (term_stats, document_stats) = posting_lists_stats(100_000);
+    let tokens = ["common", "half"];
+    let document_ids: Vec<&str> = term_stats["half"].get_document_id_set().iter().map(String::as_str).collect();
+    assert_eq!(score_two_pass(&term_stats, &document_stats, &document_ids, &tokens), score_single_pass(&term_stats, &document_stats, &document_ids, &tokens));
+
+    let mut group = c.benchmark_group("scoring \"common half\"");
+    group.bench_function("two-pass", |b| b.iter(|| {
+        score_two_pass(&term_stats, &document_stats, black_box(&document_ids), &tokens)
+    }));
+    group.bench_function("single-pass", |b| b.iter(|| {
+        score_single_pass(&term_stats, &document_stats, black_box(&document_ids), &tokens)
+    }));
+    group.finish();
 }
 
-criterion_group!(benches, criterion_benchmark);
-criterion_main!(benches);
\ No newline at end of file
+criterion_group!(benches, criterion_benchmark, cold_warm_benchmark, intersection_benchmark, scoring_benchmark, single_pass_scoring_benchmark);
+criterion_main!(benches);
@@ -1,7 +1,7 @@
-use folder_rs::Index;
+use folder_rs::{Index, IndexError};
 use serde_json::to_string;
 
-fn main() -> Result<(), ()> {
+fn main() -> Result<(), IndexError> {
     let mut index = Index::load("index")?;
     let mut result = index.search("lunar new year")?;
     result = index.search("lunar new year")?;
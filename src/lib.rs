@@ -1,7 +1,14 @@
-use core::fmt::Debug;
-use std::collections::{BTreeMap, HashSet};
-use std::fs::File;
-use std::io::Read;
+mod analyzer;
+mod storage;
+#[cfg(test)]
+mod tests;
+
+use core::cmp::Ordering;
+use core::fmt::{self, Debug, Display};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Cursor, Read, Write};
+use std::sync::OnceLock;
 use std::time::{Duration, Instant};
 
 use csv::StringRecord;
@@ -9,65 +16,162 @@ use serde_json::{Value,Map};
 use serde::Serialize;
 use json_dotpath::DotPaths;
 
-type Error = ();
+pub use analyzer::{Analyzer, AnalyzedToken, Language, TokenFilter, LowercaseFilter, PunctuationFilter, StopWordFilter, LanguageStopWordFilter, AsciiFoldingFilter, PreservingAsciiFoldingFilter, IdentifierSplitFilter};
+pub use storage::{Storage, FileStorage, EmbeddedStorage};
+#[cfg(any(feature = "zip", feature = "tar"))]
+pub use storage::ArchiveStorage;
+#[cfg(feature = "http")]
+pub use storage::HttpStorage;
+
+type Error = IndexError;
 
 type TermStats = BTreeMap<Token, TermStat>;
 type TermStatsRef<'a> = BTreeMap<Token, TermStat>;
 type DocumentID = String;
 type Token = String;
 type ShardID = u32;
+type QueryRewriter = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+#[derive(Debug)]
+pub enum IndexError {
+    NotLoaded,                  // The index has no shards, e.g. it was never loaded
+    FileNotFound(String),       // The storage has no file at the path
+    DocumentNotFound(String),   // The document is missing from its shard
+    DuplicateDocument(String),  // The document ID was loaded more than once
+    InvalidArchive,             // The archive could not be read
+    TooManyQueryTokens(usize),  // The query has more tokens than allowed
+    ChecksumMismatch { shard: ShardID, ext: String }, // The shard file does not match its recorded CRC32
+    InvalidFile(String),        // The file at the path could not be parsed
+    Io(io::Error),
+}
+
+impl Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexError::NotLoaded => write!(f, "index is not loaded"),
+            IndexError::FileNotFound(path) => write!(f, "file not found: {}", path),
+            IndexError::DocumentNotFound(id) => write!(f, "document not found: {}", id),
+            IndexError::DuplicateDocument(id) => write!(f, "duplicate document: {}", id),
+            IndexError::InvalidArchive => write!(f, "invalid archive"),
+            IndexError::TooManyQueryTokens(count) => write!(f, "too many query tokens: {}", count),
+            IndexError::ChecksumMismatch { shard, ext } => write!(f, "checksum mismatch: shard {} {}", shard, ext),
+            IndexError::InvalidFile(path) => write!(f, "invalid file: {}", path),
+            IndexError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+impl From<io::Error> for IndexError {
+    fn from(err: io::Error) -> Self {
+        IndexError::Io(err)
+    }
+}
 
 #[derive(Clone)]
 pub struct DocumentStat {
     term_frequency: BTreeMap<String, usize>,
+    boost: f64, // Multiplied into the document's final score
 }
 
 impl DocumentStat {
     pub fn new() -> DocumentStat {
         DocumentStat {
             term_frequency: BTreeMap::new(),
+            boost: 1.0,
         }
     }
 
     pub fn get_term_frequency_mut(&mut self) -> &mut BTreeMap<String, usize> {
         &mut self.term_frequency
     }
+
+    pub fn get_boost(&self) -> f64 {
+        self.boost
+    }
+
+    /// Number of indexed tokens in the document.
+    pub fn get_length(&self) -> usize {
+        self.term_frequency.values().sum()
+    }
+
+    pub fn set_boost(&mut self, boost: f64) {
+        self.boost = boost;
+    }
 }
 
 #[derive(Clone)]
 pub struct TermStat {
-    document_ids: Vec<String>,
+    document_ids: BTreeSet<String>, // Sorted and deduplicated
+    document_ids_vec: OnceLock<Vec<String>>, // Built on demand for the deprecated accessor
 }
 
 impl TermStat {
     pub fn new() -> TermStat {
         TermStat {
-            document_ids: Vec::new(),
+            document_ids: BTreeSet::new(),
+            document_ids_vec: OnceLock::new(),
         }
     }
 
+    pub fn get_document_id_set(&self) -> &BTreeSet<String> {
+        &self.document_ids
+    }
+
+    pub fn get_document_id_set_mut(&mut self) -> &mut BTreeSet<String> {
+        self.document_ids_vec = OnceLock::new();
+        &mut self.document_ids
+    }
+
+    #[deprecated(note = "use `get_document_id_set` instead")]
     pub fn get_document_ids(&self) -> &Vec<String> {
+        self.document_ids_vec.get_or_init(|| self.document_ids.iter().cloned().collect())
+    }
+
+    /// Changes made through the returned vector are written back, sorted and
+    /// deduplicated, when it is dropped.
+    #[deprecated(note = "use `get_document_id_set_mut` instead")]
+    pub fn get_document_ids_mut(&mut self) -> DocumentIdsMut<'_> {
+        let document_ids = self.document_ids.iter().cloned().collect();
+        DocumentIdsMut { term_stat: self, document_ids }
+    }
+}
+
+pub struct DocumentIdsMut<'a> {
+    term_stat: &'a mut TermStat,
+    document_ids: Vec<String>,
+}
+
+impl core::ops::Deref for DocumentIdsMut<'_> {
+    type Target = Vec<String>;
+
+    fn deref(&self) -> &Vec<String> {
         &self.document_ids
     }
+}
 
-    pub fn get_document_ids_mut(&mut self) -> &mut Vec<String> {
+impl core::ops::DerefMut for DocumentIdsMut<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<String> {
         &mut self.document_ids
     }
 }
 
-const PUNCTUATIONS: &[char]= &['!','"','#','$','%','&','(',')','*','+',',','-','.','/',':',';','<','=','>','?','@','[','\\',']','^','_','`','{','|','}','~'];
-const STOP_WORDS: &[&str] = &[
-	"a", "and", "are", "as", "at", "be", "but", "by", "for",
-	"if", "in", "into", "is", "it", "no", "not", "of", "on",
-	"or", "s", "such", "t", "that", "the", "their", "then",
-	"there", "these", "they", "this", "to", "was", "will",
-	"with", "www",
-];
+impl Drop for DocumentIdsMut<'_> {
+    fn drop(&mut self) {
+        *self.term_stat.get_document_id_set_mut() = self.document_ids.drain(..).collect();
+    }
+}
 
 const DOCUMENTS_FILE_EXTENSION : &str = "dcs";
 const DOCUMENT_STATS_FILE_EXTENSION : &str = "dst";
 const TERM_STATS_FILE_EXTENSION : &str = "tst";
 const SHARD_COUNT_FILE_NAME : &str = "shard_count";
+const CHECKSUMS_FILE_NAME : &str = "checksums";
+const TERM_SHARD_COUNT_FILE_NAME : &str = "term_shard_count";
+const DOCUMENT_COUNT_FILE_NAME : &str = "document_count";
+const DEFAULT_PATH_TEMPLATE : &str = "{name}/{shard}/{ext}";
+const MAX_PREFIX_COMPLETIONS : usize = 50; // Terms a partial last query token is completed to, see `SearchOptions::prefix_last_token`
 
 #[derive(Debug, Serialize)]
 pub struct Hit {
@@ -76,11 +180,40 @@ pub struct Hit {
     source: Value,
 }
 
+impl Hit {
+    /// The source with dotted keys and every value as a string, arrays
+    /// joined with `;` as in `SearchResult::to_csv`.
+    pub fn get_flat_source(&self) -> BTreeMap<String, String> {
+        match flatten_document(&self.source) {
+            Value::Object(fields) => fields.iter().map(|(field, value)| (field.clone(), csv_cell(Some(value)))).collect(),
+            _ => BTreeMap::new(),
+        }
+    }
+
+    /// The source encoded as MessagePack, with field names kept.
+    #[cfg(feature = "rmp-serde")]
+    pub fn source_to_msgpack(&self) -> Result<Vec<u8>, Error> {
+        rmp_serde::to_vec_named(&self.source).map_err(|err| IndexError::Io(io::Error::new(io::ErrorKind::InvalidData, err)))
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct SearchTime {
-    match_: Duration,
-    sort: Duration,
-    total: Duration,
+    #[cfg_attr(feature = "millis", serde(serialize_with = "serialize_millis"))]
+    match_: Duration, // Query analysis, term stats loading and matching
+    #[cfg_attr(feature = "millis", serde(serialize_with = "serialize_millis"))]
+    sort: Duration,   // Scoring and sorting, including document stats loading
+    #[cfg_attr(feature = "millis", serde(serialize_with = "serialize_millis"))]
+    fetch: Duration,  // Fetching the sources of the returned hits
+    #[cfg_attr(feature = "millis", serde(serialize_with = "serialize_millis"))]
+    total: Duration,  // Roughly match_ + sort + fetch
+}
+
+// With the `millis` feature durations serialize as milliseconds instead of
+// serde's default `{secs, nanos}`
+#[cfg(feature = "millis")]
+fn serialize_millis<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
 }
 
 #[derive(Debug, Serialize)]
@@ -88,17 +221,155 @@ pub struct SearchResult {
 	count: usize,
 	hits: Vec<Hit>,
 	time: SearchTime,
+	term_stats_shards: usize, // Number of distinct term stats shards the query touched
+	#[serde(skip_serializing_if = "BTreeMap::is_empty")]
+	term_stats: BTreeMap<String, TermStatSummary>, // Filled in when `return_term_stats` is set
+	#[serde(skip_serializing_if = "Option::is_none")]
+	query_tokens: Option<Vec<String>>, // Filled in when `echo_query_tokens` is set
+}
+
+/// The statistics a query term was scored with.
+#[derive(Debug, Serialize)]
+pub struct TermStatSummary {
+    document_frequency: usize,
+    inverse_document_frequency: f64,
+}
+
+impl TermStatSummary {
+    pub fn get_document_frequency(&self) -> usize {
+        self.document_frequency
+    }
+
+    pub fn get_inverse_document_frequency(&self) -> f64 {
+        self.inverse_document_frequency
+    }
 }
 
 impl SearchResult {
     pub fn len(&self) -> usize {
         return self.count;
     }
+
+    pub fn get_term_stats_shard_count(&self) -> usize {
+        self.term_stats_shards
+    }
+
+    /// The query as analyzed, see `SearchOptions::echo_query_tokens`.
+    pub fn get_query_tokens(&self) -> Option<&[String]> {
+        self.query_tokens.as_deref()
+    }
+
+    /// Statistics of each query term found in the index, see
+    /// `SearchOptions::return_term_stats`.
+    pub fn get_term_stats(&self) -> &BTreeMap<String, TermStatSummary> {
+        &self.term_stats
+    }
+
+    /// Writes the hits as CSV with an `id` and `score` column followed by the
+    /// requested source fields. Fields may be dotted paths into nested
+    /// sources; missing fields are left empty and arrays are joined with `;`.
+    pub fn to_csv(&self, fields: &[String]) -> Result<String, Error> {
+        let mut csvw = csv::Writer::from_writer(Vec::new());
+
+        let mut headers = vec!["id".to_string(), "score".to_string()];
+        headers.extend(fields.iter().cloned());
+        csvw.write_record(&headers).map_err(io::Error::from)?;
+
+        for hit in &self.hits {
+            let mut record = vec![hit.id.clone(), hit.score.to_string()];
+            for field in fields {
+                record.push(csv_cell(source_field(&hit.source, field).as_ref()));
+            }
+            csvw.write_record(&record).map_err(io::Error::from)?;
+        }
+
+        let bytes = csvw.into_inner().map_err(|err| err.into_error())?;
+        Ok(String::from_utf8(bytes).unwrap())
+    }
+}
+
+// Flattened sources keep dotted keys as is, nested ones need a path lookup
+fn source_field(source: &Value, field: &str) -> Option<Value> {
+    if let Some(value) = source.get(field) {
+        return Some(value.clone());
+    }
+    source.dot_get::<Value>(field).ok().flatten()
+}
+
+// Numbers, including the numeric strings read from CSV, compare by value and
+// come before anything else, which compares as text
+fn compare_field_values(a: &Value, b: &Value) -> Ordering {
+    let number = |value: &Value| match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse::<f64>().ok(),
+        _ => None,
+    };
+    match (number(a), number(b)) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => csv_cell(Some(a)).cmp(&csv_cell(Some(b))),
+    }
+}
+
+fn csv_cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(values)) => values.iter().map(|value| csv_cell(Some(value))).collect::<Vec<_>>().join(";"),
+        Some(value) => value.to_string(),
+    }
 }
 
 pub struct SearchOptions {
-	size: usize,  // Number of documents to return
-	from: usize,  // Starting offset for returned documents
+	pub size: usize,                                // Number of documents to return
+	pub from: usize,                                // Starting offset for returned documents
+	pub drop_zero_score: bool,                      // Leave out matched documents that scored 0.0
+	pub flatten_source: bool,                       // Return sources with dotted keys instead of nested objects
+	pub top_score_ratio: Option<f64>,               // Drop hits scoring below this fraction of the top hit's score
+	pub max_query_tokens: Option<usize>,            // Limit on analyzed query tokens, each may load a shard
+	pub query_token_limit: QueryTokenLimit,         // What to do with queries over `max_query_tokens`
+	pub term_weights: BTreeMap<String, f64>,        // Per analyzed term score multipliers, unlisted terms weigh 1.0
+	pub intersection: Intersection,                 // How the posting lists of the query terms are intersected
+	pub score_normalization: ScoreNormalization,    // Makes scores comparable across queries of different lengths
+	pub tie_breaker_field: Option<(String, Order)>, // Orders equal scores by this (dotted) source field before the ID
+	pub echo_query_tokens: bool,                    // Return the analyzed query tokens with the result
+	pub score: bool,                                // Off returns matches in ID order with score 0.0, ignoring the other scoring options
+	pub return_term_stats: bool,                    // Report each query term's document frequency and IDF
+	pub prefix_last_token: bool,                    // Match the last query token as a prefix of up to 50 terms, loads every term stats shard
+	pub parallel_scoring: bool,                     // Score matches on the rayon thread pool, needs the `rayon` feature
+	pub stop_word_weight: Option<(Language, f64)>,  // Weight of the language's stop words for analyzers keeping them, below `term_weights`
+	pub min_document_length: Option<usize>,         // Scores of documents with fewer indexed tokens are scaled by their share of it
+}
+
+/// How the documents matching every query term are found. Terms missing
+/// from the index are ignored by both.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Intersection {
+    Filter,      // Filter each term's IDs against the matches so far, in query order
+    SortedMerge, // Merge the sorted posting lists, smallest first, stopping once nothing matches
+}
+
+/// Direction of a sort.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+/// What a document's score is divided by once all query terms are added up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScoreNormalization {
+    Raw,         // Scores are left as is
+    QueryLength, // Divided by the number of query tokens
+    SummedIdf,   // Divided by the summed IDF of the query terms found in the index
+}
+
+/// How a query with more analyzed tokens than `max_query_tokens` is handled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueryTokenLimit {
+    Truncate, // Search with the first `max_query_tokens` tokens
+    Reject,   // Fail with `IndexError::TooManyQueryTokens`
 }
 
 impl Default for SearchOptions {
@@ -106,10 +377,105 @@ impl Default for SearchOptions {
         Self {
             size: 10,
             from: 0,
+            drop_zero_score: false,
+            flatten_source: false,
+            top_score_ratio: None,
+            max_query_tokens: None,
+            query_token_limit: QueryTokenLimit::Truncate,
+            term_weights: BTreeMap::new(),
+            intersection: Intersection::Filter,
+            score_normalization: ScoreNormalization::Raw,
+            tie_breaker_field: None,
+            echo_query_tokens: false,
+            score: true,
+            return_term_stats: false,
+            prefix_last_token: false,
+            parallel_scoring: false,
+            stop_word_weight: None,
+            min_document_length: None,
         }
     }
 }
 
+/// Completions of a prefix along with the hits for the best of them, see
+/// `Index::autocomplete`.
+#[derive(Debug, Serialize)]
+pub struct AutocompleteResult {
+    completions: Vec<String>,   // Most frequent first
+    hits: Option<SearchResult>, // Hits for the first completion, if any
+}
+
+impl AutocompleteResult {
+    pub fn get_completions(&self) -> &[String] {
+        &self.completions
+    }
+
+    pub fn get_hits(&self) -> Option<&SearchResult> {
+        self.hits.as_ref()
+    }
+}
+
+/// Size of a single shard, see `Index::shard_distribution`.
+#[derive(Debug, Serialize)]
+pub struct ShardInfo {
+    shard_id: ShardID,
+    document_count: usize,
+    size: usize, // Bytes across the shard's documents, document stats and term stats files
+}
+
+impl ShardInfo {
+    pub fn get_shard_id(&self) -> ShardID {
+        self.shard_id
+    }
+
+    pub fn get_document_count(&self) -> usize {
+        self.document_count
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Ranked hits whose sources are fetched one at a time as the iterator
+/// advances, so ids and scores can be used before any document shard loads.
+pub struct LazyHits<'a> {
+    index: &'a mut Index,
+    count: usize,
+    ranked: Vec<(DocumentID, f64)>,
+    position: usize,
+    flatten_source: bool,
+}
+
+impl LazyHits<'_> {
+    /// Total number of matched documents, like `SearchResult::len`.
+    pub fn get_count(&self) -> usize {
+        self.count
+    }
+
+    /// IDs and scores of the requested page in ranked order.
+    pub fn get_ranked(&self) -> &[(DocumentID, f64)] {
+        &self.ranked
+    }
+}
+
+impl Iterator for LazyHits<'_> {
+    type Item = Result<Hit, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (id, score) = self.ranked.get(self.position)?.clone();
+        self.position += 1;
+
+        let flatten_source = self.flatten_source;
+        let index = &mut *self.index;
+        let source = fetch_document(&index.source, &mut index.documents, &mut index.loaded_documents_shards, &id, index.shard_count)
+            .map(|source| if flatten_source { flatten_document(&source) } else { source });
+        let [documents_shard, _] = index.document_shards(&id);
+        index.enforce_max_loaded_bytes(&[documents_shard]);
+        Some(source.map(|source| Hit{ id, score, source }))
+    }
+}
+
 trait AnalyzableField {
     fn analyze(&self, parent_field_name: &str, m: &mut BTreeMap<String, Vec<String>>);
 }
@@ -170,16 +536,144 @@ impl AnalyzableField for Value {
     }
 }
 
+/// What to do when a document ID appears more than once in a shard. A copy
+/// in another shard than the one the ID routes to never replaces the copy in
+/// that home shard, whatever order the shards are loaded in, and is only used
+/// until the home shard is loaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DuplicatePolicy {
+    #[default]
+    Merge,      // Later fields and term frequencies in the shard replace earlier ones
+    KeepFirst,  // The shard's first copy wins
+    Error,      // Loading fails with `IndexError::DuplicateDocument`, also for copies in different shards
+}
+
+/// What to do when a shard file does not exist in the storage.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MissingShardPolicy {
+    #[default]
+    Error,  // Fail with `IndexError::FileNotFound`
+    Ignore, // Treat the shard as empty
+}
+
+/// How document IDs are normalized, both when loaded from the shards and
+/// when passed in to be fetched. Documents are routed to shards by their
+/// normalized ID, so the index has to be written with the same normalization.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IdNormalization {
+    #[default]
+    Keep,          // IDs are used as they are
+    Trim,          // Surrounding whitespace is removed
+    TrimLowercase, // Surrounding whitespace is removed and the rest lowercased
+}
+
+impl IdNormalization {
+    pub fn normalize<'a>(&self, document_id: &'a str) -> Cow<'a, str> {
+        match self {
+            IdNormalization::Keep => Cow::Borrowed(document_id),
+            IdNormalization::Trim => Cow::Borrowed(document_id.trim()),
+            IdNormalization::TrimLowercase => {
+                let document_id = document_id.trim();
+                if document_id.chars().any(char::is_uppercase) {
+                    Cow::Owned(document_id.to_lowercase())
+                } else {
+                    Cow::Borrowed(document_id)
+                }
+            },
+        }
+    }
+}
+
+/// Where an index's shard files are read from and how they are loaded.
+pub struct ShardSource {
+    name: String,
+    storage: Box<dyn Storage>,
+    path_template: String,
+    duplicate_policy: DuplicatePolicy,
+    missing_document_stats: MissingShardPolicy,
+    id_normalization: IdNormalization,
+    total_documents: Option<usize>,     // Set by the caller, takes precedence over the counted documents
+    stored_documents: Option<usize>,    // Read from the index's `document_count` file
+    counted_documents: OnceLock<usize>, // Counted from the document stats shards when neither of the above is known
+    checksums: BTreeMap<(ShardID, String), u32>,
+    verify_checksums: bool,
+}
+
+impl Default for ShardSource {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            storage: Default::default(),
+            path_template: DEFAULT_PATH_TEMPLATE.to_string(),
+            duplicate_policy: Default::default(),
+            missing_document_stats: Default::default(),
+            id_normalization: Default::default(),
+            total_documents: None,
+            stored_documents: None,
+            counted_documents: OnceLock::new(),
+            checksums: BTreeMap::new(),
+            verify_checksums: true,
+        }
+    }
+}
+
+impl ShardSource {
+    fn shard_path(&self, shard_id: ShardID, extension: &str) -> String {
+        shard_path(&self.path_template, &self.name, shard_id, extension)
+    }
+
+    fn open_shard(&self, shard_id: ShardID, extension: &str) -> Result<Box<dyn Read + '_>, Error> {
+        let mut file = self.storage.open(&self.shard_path(shard_id, extension))?;
+
+        let checksum = match self.checksums.get(&(shard_id, extension.to_string())) {
+            Some(checksum) if self.verify_checksums => *checksum,
+            _ => return Ok(file),
+        };
+
+        // The whole file has to be read to check it before any of it is parsed
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        if crc32fast::hash(&bytes) != checksum {
+            return Err(IndexError::ChecksumMismatch { shard: shard_id, ext: extension.to_string() });
+        }
+        Ok(Box::new(Cursor::new(bytes)))
+    }
+}
+
+/// Expands a shard path template. `{name}`, `{shard}` and `{ext}` are
+/// replaced by the index name, the shard ID and the file extension.
+fn shard_path(path_template: &str, index_name: &str, shard_id: ShardID, extension: &str) -> String {
+    path_template
+        .replace("{name}", index_name)
+        .replace("{shard}", &shard_id.to_string())
+        .replace("{ext}", extension)
+}
+
 #[derive(Default)]
 pub struct Index {
-    name: String,
+    source: ShardSource,
     documents: BTreeMap<DocumentID, Value>,
     document_stats: BTreeMap<DocumentID, DocumentStat>,
     term_stats: TermStats,
-    shard_count: usize,
-    loaded_documents_shards: BTreeMap<usize, bool>,
-    loaded_document_stats_shards: BTreeMap<usize, bool>,
-    loaded_term_stats_shards: BTreeMap<usize, bool>,
+    shard_count: usize,      // Shards of the documents and document stats
+    term_shard_count: usize, // Shards of the term stats, the same as shard_count unless given
+    loaded_documents_shards: BTreeMap<usize, usize>,      // Bytes read from each loaded shard, roughly the memory it takes up
+    loaded_document_stats_shards: BTreeMap<usize, usize>,
+    loaded_term_stats_shards: BTreeMap<usize, usize>,
+    analyzer: Analyzer,
+    query_rewriter: Option<QueryRewriter>,
+    max_loaded_bytes: Option<usize>,                       // Budget for the loaded shards, over it the least recently used are dropped
+    shard_last_used: BTreeMap<(ShardKind, usize), u64>,    // Last use of each loaded shard, tracked with a budget
+    uses: u64,                                             // Clock for the shards' last use, ticks on every call loading shards
+    reindexed: bool,                                       // The stats were rebuilt in memory and can't be loaded again
+}
+
+/// The kinds of shard files an index is made of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ShardKind {
+    Documents,
+    DocumentStats,
+    TermStats,
 }
 
 impl Index {
@@ -187,10 +681,27 @@ impl Index {
         Default::default()
     }
 
+    /// Loads the index from the filesystem, or with the `http` feature from
+    /// an `http://` or `https://` URL. Only the small metadata files are read
+    /// here, shards are loaded as searches need them. IDF needs the number of
+    /// documents in the whole index: without a `document_count` file or
+    /// `set_total_documents`, the first scored search reads every document
+    /// stats shard once to count them, one request per shard over HTTP.
     pub fn load(index_name: &str) -> Result<Self, Error> {
+        #[cfg(feature = "http")]
+        if index_name.starts_with("http://") || index_name.starts_with("https://") {
+            return Self::load_with_storage(index_name, HttpStorage::new());
+        }
+        Self::load_with_storage(index_name, FileStorage)
+    }
+
+    pub fn load_with_storage<S: Storage + 'static>(index_name: &str, storage: S) -> Result<Self, Error> {
         let mut index = Self::new();
-        index.name = index_name.to_string();
+        index.source.name = index_name.to_string();
+        index.source.storage = Box::new(storage);
         index.load_shard_count()?;
+        index.load_checksums()?;
+        index.load_document_count()?;
         Ok(index)
     }
 
@@ -198,60 +709,635 @@ impl Index {
         self.shard_count
     }
 
+    pub fn get_term_shard_count(&self) -> usize {
+        self.term_shard_count
+    }
+
+    pub fn get_analyzer_mut(&mut self) -> &mut Analyzer {
+        &mut self.analyzer
+    }
+
+    /// Sets a function that rewrites the raw query, e.g. expanding
+    /// abbreviations, before it is analyzed. It runs for every search.
+    pub fn set_query_rewriter<F: Fn(&str) -> String + Send + Sync + 'static>(&mut self, query_rewriter: F) {
+        self.query_rewriter = Some(Box::new(query_rewriter));
+    }
+
+    pub fn set_duplicate_policy(&mut self, duplicate_policy: DuplicatePolicy) {
+        self.source.duplicate_policy = duplicate_policy;
+    }
+
+    /// Sets what happens when a document stats shard is missing. With
+    /// `MissingShardPolicy::Ignore` its documents are still returned but
+    /// unscored. Defaults to `MissingShardPolicy::Error`.
+    pub fn set_missing_document_stats_policy(&mut self, policy: MissingShardPolicy) {
+        self.source.missing_document_stats = policy;
+        // Missing shards change how documents are counted
+        self.source.counted_documents = OnceLock::new();
+    }
+
+    /// Sets how document IDs are normalized. Set it before searching, IDs
+    /// already loaded are not normalized again. Defaults to
+    /// `IdNormalization::Keep`.
+    pub fn set_id_normalization(&mut self, id_normalization: IdNormalization) {
+        self.source.id_normalization = id_normalization;
+        // Normalizing may merge IDs counted apart
+        self.source.counted_documents = OnceLock::new();
+    }
+
+    /// Sets the number of documents in the whole index for IDF to use. It
+    /// takes precedence over the index's `document_count` file and over
+    /// counting the rows of every document stats shard, which the first
+    /// scored search does otherwise. `None` goes back to those.
+    pub fn set_total_documents(&mut self, total_documents: Option<usize>) {
+        self.source.total_documents = total_documents;
+    }
+
+    /// Bounds the approximate memory of the loaded documents and stats, taken
+    /// as the size of the shard files read. After each search, fetch or other
+    /// call loading shards the least recently used ones are dropped until the
+    /// loaded ones fit, and are loaded again when needed. Stats rebuilt by
+    /// `reindex` are never dropped as they can't be loaded again, only the
+    /// documents are. `None`, the default, keeps every loaded shard.
+    pub fn set_max_loaded_bytes(&mut self, max_loaded_bytes: Option<usize>) {
+        self.max_loaded_bytes = max_loaded_bytes;
+        self.shard_last_used.clear();
+    }
+
+    /// Turns checking shard files against the index's `checksums` file on or
+    /// off. Verification needs each shard read fully before parsing, so it can
+    /// be skipped for speed. Defaults to on.
+    pub fn set_verify_checksums(&mut self, verify_checksums: bool) {
+        self.source.verify_checksums = verify_checksums;
+    }
+
+    /// Sets the layout of the shard files, e.g. `{name}/shard-{shard}.{ext}`.
+    /// Defaults to `{name}/{shard}/{ext}`. The `shard_count` file is always
+    /// read from `{name}/shard_count`.
+    pub fn set_path_template(&mut self, path_template: &str) {
+        self.source.path_template = path_template.to_string();
+    }
+
+    /// Reports the number of documents and the file size of every shard so a
+    /// badly balanced index can be spotted. The shard files are read but not
+    /// kept in memory.
+    pub fn shard_distribution(&self) -> Result<Vec<ShardInfo>, Error> {
+        if self.shard_count == 0 {
+            return Err(IndexError::NotLoaded);
+        }
+
+        let shard_count = self.shard_count.max(self.term_shard_count);
+        let mut shards = Vec::with_capacity(shard_count);
+        for shard_id in 0..shard_count as ShardID {
+            let mut document_count = 0;
+            let mut size = 0;
+
+            if (shard_id as usize) < self.shard_count {
+                let mut documents = Vec::new();
+                self.source.open_shard(shard_id, DOCUMENTS_FILE_EXTENSION)?.read_to_end(&mut documents)?;
+                document_count = csv::Reader::from_reader(documents.as_slice()).records().count();
+                size += documents.len();
+                size += io::copy(&mut self.source.open_shard(shard_id, DOCUMENT_STATS_FILE_EXTENSION)?, &mut io::sink())? as usize;
+            }
+            if (shard_id as usize) < self.term_shard_count {
+                size += io::copy(&mut self.source.open_shard(shard_id, TERM_STATS_FILE_EXTENSION)?, &mut io::sink())? as usize;
+            }
+
+            shards.push(ShardInfo{ shard_id, document_count, size });
+        }
+
+        Ok(shards)
+    }
+
+    /// Counts the documents matching `query` in each document shard, without
+    /// scoring them or fetching their sources. Shards without matches are
+    /// left out.
+    pub fn count_by_shard(&mut self, query: &str) -> Result<BTreeMap<usize, usize>, Error> {
+        if self.shard_count == 0 {
+            return Err(IndexError::NotLoaded);
+        }
+
+        let query = self.rewrite_query(query);
+        let tmp = self.analyzer.analyze(&query);
+        let tokens: Vec<&str> = tmp.iter().map(String::as_str).collect();
+        let shard_ids: BTreeSet<ShardID> = tokens.iter().map(|token| calculate_shard_id(token, self.term_shard_count as u32)).collect();
+        for shard_id in shard_ids {
+            load_term_stats_from_shard(&self.source, &mut self.term_stats, &mut self.loaded_term_stats_shards, shard_id)?;
+        }
+
+        let mut counts = BTreeMap::new();
+        for document_id in find_documents(&self.term_stats, &tokens)? {
+            let shard_id = calculate_shard_id(document_id, self.shard_count as u32) as usize;
+            *counts.entry(shard_id).or_insert(0) += 1;
+        }
+
+        let used_shards: Vec<(ShardKind, usize)> = tokens.iter().map(|token| self.term_stats_shard(token)).collect();
+        self.enforce_max_loaded_bytes(&used_shards);
+        Ok(counts)
+    }
+
+    /// Runs every string field of the document through the configured
+    /// analyzer, keyed by dotted field name. Empty tokens are left out.
+    pub fn analyze_document(&self, document: &Value) -> BTreeMap<String, Vec<String>> {
+        let mut fields: BTreeMap<String, Vec<String>> = match flatten_document(document) {
+            Value::Object(flattened) => flattened.keys().map(|field| (field.clone(), Vec::new())).collect(),
+            _ => BTreeMap::new(),
+        };
+        document.analyze("", &mut fields);
+
+        for values in fields.values_mut() {
+            *values = values.iter()
+                .flat_map(|value| self.analyzer.analyze(value))
+                .filter(|token| !token.is_empty())
+                .collect();
+        }
+        fields
+    }
+
+    /// Runs text through the configured analyzer like a query would be,
+    /// keeping each token's byte offsets and position in the input.
+    pub fn debug_analyze(&self, text: &str) -> Vec<AnalyzedToken> {
+        self.analyzer.analyze_with_offsets(text)
+    }
+
+    /// Loads every shard up front so later searches do no I/O.
+    pub fn warm(&mut self) -> Result<(), Error> {
+        if self.shard_count == 0 {
+            return Err(IndexError::NotLoaded);
+        }
+
+        for shard_id in 0..self.shard_count as ShardID {
+            load_documents_from_shard(&self.source, &mut self.documents, &mut self.loaded_documents_shards, shard_id, self.shard_count)?;
+            load_document_stats_from_shard(&self.source, &mut self.document_stats, &mut self.loaded_document_stats_shards, shard_id, self.shard_count)?;
+        }
+        for shard_id in 0..self.term_shard_count as ShardID {
+            load_term_stats_from_shard(&self.source, &mut self.term_stats, &mut self.loaded_term_stats_shards, shard_id)?;
+        }
+
+        self.enforce_max_loaded_bytes(&[]);
+        Ok(())
+    }
+
+    /// Rebuilds the document and term stats by running the current analyzer
+    /// over every field of every stored document, e.g. after changing the
+    /// analyzer's filters. Document boosts are kept. The rebuilt stats only
+    /// live in memory and replace the shard files for this index, so they
+    /// stay loaded whatever `set_max_loaded_bytes` allows.
+    pub fn reindex(&mut self) -> Result<(), Error> {
+        if self.shard_count == 0 {
+            return Err(IndexError::NotLoaded);
+        }
+
+        for shard_id in 0..self.shard_count as ShardID {
+            load_documents_from_shard(&self.source, &mut self.documents, &mut self.loaded_documents_shards, shard_id, self.shard_count)?;
+            load_document_stats_from_shard(&self.source, &mut self.document_stats, &mut self.loaded_document_stats_shards, shard_id, self.shard_count)?;
+        }
+
+        let mut document_stats = BTreeMap::new();
+        let mut term_stats = TermStats::new();
+        for (document_id, document) in &self.documents {
+            let mut document_stat = DocumentStat::new();
+            if let Some(old_document_stat) = self.document_stats.get(document_id) {
+                document_stat.set_boost(old_document_stat.get_boost());
+            }
+
+            let mut values = Vec::new();
+            collect_string_values(document, &mut values);
+            for token in values.into_iter().flat_map(|value| self.analyzer.analyze(value)) {
+                if token.is_empty() {
+                    continue;
+                }
+                *document_stat.get_term_frequency_mut().entry(token.clone()).or_insert(0) += 1;
+                term_stats.entry(token).or_insert_with(TermStat::new).get_document_id_set_mut().insert(document_id.clone());
+            }
+
+            document_stats.insert(document_id.clone(), document_stat);
+        }
+
+        self.document_stats = document_stats;
+        self.term_stats = term_stats;
+        self.reindexed = true;
+        // Keep the rebuilt term stats from being mixed with the ones on disk
+        for shard_id in 0..self.term_shard_count {
+            self.loaded_term_stats_shards.insert(shard_id, 0);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the IDs of the documents containing the term, loading its term
+    /// stats shard if needed. The token is looked up as given and is not run
+    /// through the analyzer, so pass an already analyzed term.
+    pub fn term_stat(&mut self, token: &str) -> Result<Option<Vec<String>>, Error> {
+        if self.shard_count == 0 {
+            return Err(IndexError::NotLoaded);
+        }
+
+        let term_stat = fetch_term_stat(&self.source, &mut self.term_stats, &mut self.loaded_term_stats_shards, token, self.term_shard_count)?;
+        let document_ids = term_stat.map(|term_stat| term_stat.get_document_id_set().iter().cloned().collect());
+
+        let used_shard = self.term_stats_shard(token);
+        self.enforce_max_loaded_bytes(&[used_shard]);
+        Ok(document_ids)
+    }
+
+    /// Returns up to `size` terms starting with `prefix`, the ones found in
+    /// the most documents first. Terms are spread over the shards by hash, so
+    /// the first call loads every term stats shard.
+    pub fn terms_with_prefix(&mut self, prefix: &str, size: usize) -> Result<Vec<String>, Error> {
+        if self.shard_count == 0 {
+            return Err(IndexError::NotLoaded);
+        }
+
+        for shard_id in 0..self.term_shard_count as ShardID {
+            load_term_stats_from_shard(&self.source, &mut self.term_stats, &mut self.loaded_term_stats_shards, shard_id)?;
+        }
+
+        let mut terms = terms_starting_with(&self.term_stats, prefix);
+        terms.sort_by(|a, b| document_frequency(&self.term_stats, b).total_cmp(&document_frequency(&self.term_stats, a)).then_with(|| a.cmp(b)));
+        let terms = terms.into_iter().take(size).map(str::to_string).collect();
+
+        let used_shards: Vec<(ShardKind, usize)> = (0..self.term_shard_count).map(|shard_id| (ShardKind::TermStats, shard_id)).collect();
+        self.enforce_max_loaded_bytes(&used_shards);
+        Ok(terms)
+    }
+
+    /// Completes the last word of `prefix` with `terms_with_prefix` and
+    /// searches for the best completion, returning up to `opts.size` of each.
+    /// Like `terms_with_prefix` this loads every term stats shard, plus the
+    /// document shards of the returned hits.
+    pub fn autocomplete(&mut self, prefix: &str, opts: SearchOptions) -> Result<AutocompleteResult, Error> {
+        let prefix = match self.analyzer.analyze(prefix).pop() {
+            Some(prefix) if !prefix.is_empty() => prefix,
+            _ => return Ok(AutocompleteResult{ completions: Vec::new(), hits: None }),
+        };
+
+        let completions = self.terms_with_prefix(&prefix, opts.size)?;
+        let hits = match completions.first() {
+            Some(completion) => Some(self.search_with_options(&completion.clone(), opts)?),
+            None => None,
+        };
+
+        Ok(AutocompleteResult{ completions, hits })
+    }
+
+    /// Returns the sources of the documents in the order of `document_ids`.
+    pub fn fetch_documents(&mut self, document_ids: &[&str]) -> Result<Vec<Value>, Error> {
+        let documents = fetch_documents(&self.source, &mut self.documents, &mut self.loaded_documents_shards, document_ids, self.shard_count)?;
+
+        if self.max_loaded_bytes.is_some() {
+            let used_shards: Vec<(ShardKind, usize)> = document_ids.iter().map(|document_id| self.document_shards(document_id)[0]).collect();
+            self.enforce_max_loaded_bytes(&used_shards);
+        }
+        Ok(documents)
+    }
+
+    /// Searches with the default options. Only the shards the query and its
+    /// hits need are loaded, except that the first scored search of an index
+    /// without a known document count reads every document stats shard, see
+    /// `Index::load`.
     pub fn search(&mut self, query: &str) -> Result<SearchResult, Error> {
-        search_with_options(query, SearchOptions::default(), &self.name, &mut self.documents, &mut self.loaded_documents_shards, &mut self.document_stats, &mut self.loaded_document_stats_shards, &mut self.term_stats, &mut self.loaded_term_stats_shards, self.shard_count)
+        self.search_with_options(query, SearchOptions::default())
+    }
+
+    /// Ranks the matching documents up front but only fetches a hit's source
+    /// when the returned iterator is advanced to it.
+    pub fn search_lazy(&mut self, query: &str, opts: SearchOptions) -> Result<LazyHits<'_>, Error> {
+        let query = self.rewrite_query(query);
+        let ranking = rank_documents(&query, &opts, &self.source, &self.analyzer, &mut self.documents, &mut self.loaded_documents_shards, &mut self.document_stats, &mut self.loaded_document_stats_shards, &mut self.term_stats, &mut self.loaded_term_stats_shards, self.shard_count, self.term_shard_count)?;
+        let count = ranking.document_ids.len();
+        let ranked: Vec<(DocumentID, f64)> = ranking.document_ids.into_iter()
+            .zip(ranking.scores)
+            .skip(opts.from)
+            .take(opts.size)
+            .collect();
+
+        if self.max_loaded_bytes.is_some() {
+            let mut used_shards: Vec<(ShardKind, usize)> = ranking.query_tokens.iter().map(|token| self.term_stats_shard(token)).collect();
+            for (document_id, _) in &ranked {
+                used_shards.extend(self.document_shards(document_id));
+            }
+            self.enforce_max_loaded_bytes(&used_shards);
+        }
+        Ok(LazyHits {
+            index: self,
+            count,
+            ranked,
+            position: 0,
+            flatten_source: opts.flatten_source,
+        })
+    }
+
+    /// Writes each hit as a line of JSON as soon as its source is fetched
+    /// instead of collecting them into a `SearchResult`. Returns the total
+    /// number of matched documents.
+    pub fn search_stream<W: Write>(&mut self, query: &str, opts: SearchOptions, mut writer: W) -> Result<usize, Error> {
+        let hits = self.search_lazy(query, opts)?;
+        let count = hits.get_count();
+        for hit in hits {
+            serde_json::to_writer(&mut writer, &hit?).map_err(io::Error::from)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(count)
+    }
+
+    pub fn search_with_options(&mut self, query: &str, opts: SearchOptions) -> Result<SearchResult, Error> {
+        let query = self.rewrite_query(query);
+        let result = search_with_options(&query, opts, &self.source, &self.analyzer, &mut self.documents, &mut self.loaded_documents_shards, &mut self.document_stats, &mut self.loaded_document_stats_shards, &mut self.term_stats, &mut self.loaded_term_stats_shards, self.shard_count, self.term_shard_count)?;
+
+        if self.max_loaded_bytes.is_some() {
+            let mut used_shards: Vec<(ShardKind, usize)> = self.analyzer.analyze(&query).iter().map(|token| self.term_stats_shard(token)).collect();
+            for hit in &result.hits {
+                used_shards.extend(self.document_shards(&hit.id));
+            }
+            self.enforce_max_loaded_bytes(&used_shards);
+        }
+        Ok(result)
+    }
+
+    // Marks newly loaded shards and `used_shards` as just used and drops the
+    // least recently used shards while over `max_loaded_bytes`
+    fn enforce_max_loaded_bytes(&mut self, used_shards: &[(ShardKind, usize)]) {
+        let max_loaded_bytes = match self.max_loaded_bytes {
+            Some(max_loaded_bytes) => max_loaded_bytes,
+            None => return,
+        };
+        self.uses += 1;
+
+        let loaded_shards: BTreeMap<(ShardKind, usize), usize> = self.loaded_documents_shards.iter().map(|(shard_id, bytes)| ((ShardKind::Documents, *shard_id), *bytes))
+            .chain(self.loaded_document_stats_shards.iter().map(|(shard_id, bytes)| ((ShardKind::DocumentStats, *shard_id), *bytes)))
+            .chain(self.loaded_term_stats_shards.iter().map(|(shard_id, bytes)| ((ShardKind::TermStats, *shard_id), *bytes)))
+            .collect();
+        self.shard_last_used.retain(|shard, _| loaded_shards.contains_key(shard));
+        for shard in loaded_shards.keys() {
+            self.shard_last_used.entry(*shard).or_insert(self.uses);
+        }
+        for shard in used_shards {
+            if let Some(last_used) = self.shard_last_used.get_mut(shard) {
+                *last_used = self.uses;
+            }
+        }
+
+        let mut loaded_bytes: usize = loaded_shards.values().sum();
+        let mut shards: Vec<((ShardKind, usize), u64)> = self.shard_last_used.iter().map(|(shard, last_used)| (*shard, *last_used)).collect();
+        shards.sort_by_key(|(_, last_used)| *last_used);
+        for (shard, _) in shards {
+            if self.reindexed && shard.0 != ShardKind::Documents {
+                continue;
+            }
+            if loaded_bytes <= max_loaded_bytes {
+                break;
+            }
+            self.unload_shard(shard);
+            loaded_bytes -= loaded_shards[&shard];
+        }
+    }
+
+    fn term_stats_shard(&self, token: &str) -> (ShardKind, usize) {
+        (ShardKind::TermStats, calculate_shard_id(token, self.term_shard_count as u32) as usize)
+    }
+
+    // The shards holding a document's source and stats
+    fn document_shards(&self, document_id: &str) -> [(ShardKind, usize); 2] {
+        let shard_id = calculate_shard_id(&self.source.id_normalization.normalize(document_id), self.shard_count as u32) as usize;
+        [(ShardKind::Documents, shard_id), (ShardKind::DocumentStats, shard_id)]
+    }
+
+    fn unload_shard(&mut self, (kind, shard_id): (ShardKind, usize)) {
+        let (shard_count, term_shard_count) = (self.shard_count as u32, self.term_shard_count as u32);
+        let in_shard = |key: &str, shard_count: u32| calculate_shard_id(key, shard_count) as usize == shard_id;
+        match kind {
+            ShardKind::Documents => {
+                self.documents.retain(|document_id, _| !in_shard(document_id, shard_count));
+                self.loaded_documents_shards.remove(&shard_id);
+            },
+            ShardKind::DocumentStats => {
+                self.document_stats.retain(|document_id, _| !in_shard(document_id, shard_count));
+                self.loaded_document_stats_shards.remove(&shard_id);
+            },
+            ShardKind::TermStats => {
+                self.term_stats.retain(|term, _| !in_shard(term, term_shard_count));
+                self.loaded_term_stats_shards.remove(&shard_id);
+            },
+        }
+        self.shard_last_used.remove(&(kind, shard_id));
+    }
+
+    fn rewrite_query(&self, query: &str) -> String {
+        match &self.query_rewriter {
+            Some(query_rewriter) => query_rewriter(query),
+            None => query.to_string(),
+        }
     }
 
     fn load_shard_count(&mut self) -> Result<(), Error> {
-        let file_path = format!("{}/{}", &self.name, SHARD_COUNT_FILE_NAME);
-        let file = File::open(&file_path).unwrap();
-        self.load_shard_count_from_reader(file)?;
+        let file_path = format!("{}/{}", &self.source.name, SHARD_COUNT_FILE_NAME);
+        let file = self.source.storage.open(&file_path)?;
+        self.shard_count = load_shard_count_from_reader(file, &file_path)?;
+
+        // Terms may be sharded more finely than documents
+        let file_path = format!("{}/{}", &self.source.name, TERM_SHARD_COUNT_FILE_NAME);
+        self.term_shard_count = match self.source.storage.open(&file_path) {
+            Ok(file) => load_shard_count_from_reader(file, &file_path)?,
+            Err(IndexError::FileNotFound(_)) => self.shard_count,
+            Err(err) => return Err(err),
+        };
+        Ok(())
+    }
+
+    // The checksums file is optional, without it shards are not verified
+    fn load_checksums(&mut self) -> Result<(), Error> {
+        let file_path = format!("{}/{}", &self.source.name, CHECKSUMS_FILE_NAME);
+        let file = match self.source.storage.open(&file_path) {
+            Ok(file) => file,
+            Err(IndexError::FileNotFound(_)) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        self.source.checksums = load_checksums_from_reader(file, &file_path)?;
         Ok(())
     }
 
-    fn load_shard_count_from_reader<T: Read>(&mut self, mut r: T) -> Result<(), Error> {
-        let mut s = String::new();
-        r.read_to_string(&mut s).unwrap();
-        self.shard_count = s.parse::<usize>().unwrap() as usize;
+    // The document count file is optional, without it the document stats
+    // shards are counted when IDF first needs the total
+    fn load_document_count(&mut self) -> Result<(), Error> {
+        let file_path = format!("{}/{}", &self.source.name, DOCUMENT_COUNT_FILE_NAME);
+        let file = match self.source.storage.open(&file_path) {
+            Ok(file) => file,
+            Err(IndexError::FileNotFound(_)) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        self.source.stored_documents = Some(load_shard_count_from_reader(file, &file_path)?);
         Ok(())
     }
 }
 
-fn analyze(s: &str) -> Vec<String> {
-    s.split(&[',', '、', '　', ' '][..])
-        .map(|s| s.to_lowercase())
-        .map(|s| s.replace(PUNCTUATIONS, ""))
-        .filter(|s| !STOP_WORDS.contains(&s.as_str()))
-        .collect()
+// Rows are `shard,ext,checksum` with the CRC32 of the shard file in hex
+fn load_checksums_from_reader<T: Read>(r: T, file_path: &str) -> Result<BTreeMap<(ShardID, String), u32>, Error> {
+    let mut csvr = csv::Reader::from_reader(r);
+    let mut checksums = BTreeMap::new();
+    let invalid = || IndexError::InvalidFile(file_path.to_string());
+
+    for result in csvr.records() {
+        let record = result.map_err(|_| invalid())?;
+        let shard_id: ShardID = record.get(0).and_then(|shard_id| shard_id.parse().ok()).ok_or_else(invalid)?;
+        let extension = record.get(1).ok_or_else(invalid)?.to_string();
+        let checksum = record.get(2).and_then(|checksum| u32::from_str_radix(checksum, 16).ok()).ok_or_else(invalid)?;
+        checksums.insert((shard_id, extension), checksum);
+    }
+
+    Ok(checksums)
+}
+
+// The count files hold a single number, usually followed by a newline
+fn load_shard_count_from_reader<T: Read>(mut r: T, file_path: &str) -> Result<usize, Error> {
+    let mut s = String::new();
+    r.read_to_string(&mut s)?;
+    let shard_count = s.trim().parse::<usize>().map_err(|_| IndexError::InvalidFile(file_path.to_string()))?;
+    Ok(shard_count)
 }
 
-pub fn search_with_options(query: &str, opts: SearchOptions, index_name: &str, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, bool>, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, bool>, term_stats: &mut TermStats, loaded_term_stats_shards: &mut BTreeMap<usize, bool>, shard_count: usize) -> Result<SearchResult, Error> {
+pub fn search_with_options(query: &str, opts: SearchOptions, source: &ShardSource, analyzer: &Analyzer, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, usize>, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, usize>, term_stats: &mut TermStats, loaded_term_stats_shards: &mut BTreeMap<usize, usize>, shard_count: usize, term_shard_count: usize) -> Result<SearchResult, Error> {
     let start_time = Instant::now();
-    let tmp = analyze(query);
-    let tokens: Vec<&str> = tmp.iter().map(String::as_str).collect();
-    for token in &tokens {
-        let shard_id = calculate_shard_id(token, shard_count as u32);
-        load_term_stats_from_shard(index_name, term_stats, loaded_term_stats_shards, shard_id)?;
-    }
-    let (matched_document_ids, match_duration) = find_documents(term_stats, &tokens)?;
-    let (sorted_document_ids, scores, sort_duration) = sort_documents(&index_name, documents, document_stats, loaded_document_stats_shards, term_stats, shard_count, &matched_document_ids, &tokens)?;
-    let hits = fetch_hits(index_name, documents, loaded_documents_shards, shard_count, &sorted_document_ids, scores, opts.size, opts.from)?;
-    let count = sorted_document_ids.len();
+    let ranking = rank_documents(query, &opts, source, analyzer, documents, loaded_documents_shards, document_stats, loaded_document_stats_shards, term_stats, loaded_term_stats_shards, shard_count, term_shard_count)?;
+    let fetch_start_time = Instant::now();
+    let hits = fetch_hits(source, documents, loaded_documents_shards, shard_count, &ranking.document_ids, ranking.scores, &opts)?;
+    let fetch_duration = fetch_start_time.elapsed();
+    let count = ranking.document_ids.len();
     let total_duration = start_time.elapsed();
     Ok(SearchResult{
         count,
         hits,
         time: SearchTime{
-            match_: match_duration,
-            sort: sort_duration,
+            match_: ranking.match_duration,
+            sort: ranking.sort_duration,
+            fetch: fetch_duration,
             total: total_duration,
-        }
+        },
+        term_stats_shards: ranking.term_stats_shards,
+        term_stats: ranking.term_stats,
+        query_tokens: if opts.echo_query_tokens { Some(ranking.query_tokens) } else { None },
     })
 }
 
-fn find_documents<'a>(term_stats: &'a TermStats, tokens: &[&str]) -> Result<(Vec<&'a str>, Duration), Error> {
+// Matched documents in ranked order, before any sources are fetched
+struct Ranking {
+    document_ids: Vec<DocumentID>,
+    scores: Vec<f64>,
+    match_duration: Duration,
+    sort_duration: Duration,
+    term_stats_shards: usize,
+    term_stats: BTreeMap<String, TermStatSummary>,
+    query_tokens: Vec<String>,
+}
+
+fn rank_documents(query: &str, opts: &SearchOptions, source: &ShardSource, analyzer: &Analyzer, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, usize>, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, usize>, term_stats: &mut TermStats, loaded_term_stats_shards: &mut BTreeMap<usize, usize>, shard_count: usize, term_shard_count: usize) -> Result<Ranking, Error> {
+    if shard_count == 0 {
+        return Err(IndexError::NotLoaded);
+    }
+
     let start_time = Instant::now();
-    let mut document_ids_set = HashSet::new();
+    let mut tmp = analyzer.analyze(query);
+    if let Some(max_query_tokens) = opts.max_query_tokens {
+        if tmp.len() > max_query_tokens {
+            match opts.query_token_limit {
+                QueryTokenLimit::Truncate => tmp.truncate(max_query_tokens),
+                QueryTokenLimit::Reject => return Err(IndexError::TooManyQueryTokens(tmp.len())),
+            }
+        }
+    }
+    let mut tokens: Vec<&str> = tmp.iter().map(String::as_str).collect();
+    // The last token may still be being typed, so it matches any term it
+    // prefixes. A query ending in a separator has no partial last token.
+    let prefix = if opts.prefix_last_token { tokens.pop().filter(|prefix| !prefix.trim().is_empty()) } else { None };
+    // Counted before any completions are added, for `QueryLength`
+    let mut query_length = tokens.len();
+    let mut shard_ids: BTreeSet<ShardID> = tokens.iter().map(|token| calculate_shard_id(token, term_shard_count as u32)).collect();
+    if prefix.is_some() {
+        // Terms are spread over the shards by hash, any of them may hold a completion
+        shard_ids.extend(0..term_shard_count as ShardID);
+    }
+    for shard_id in &shard_ids {
+        load_term_stats_from_shard(source, term_stats, loaded_term_stats_shards, *shard_id)?;
+    }
+    let mut matched_document_ids = match opts.intersection {
+        Intersection::Filter => find_documents(term_stats, &tokens)?,
+        Intersection::SortedMerge => find_documents_sorted_merge(term_stats, &tokens),
+    };
+    if let Some(prefix) = prefix {
+        // Only the most frequent completions are kept, each one is scored
+        let mut completions = terms_starting_with(term_stats, prefix);
+        completions.sort_by(|a, b| document_frequency(term_stats, b).total_cmp(&document_frequency(term_stats, a)).then_with(|| a.cmp(b)));
+        completions.truncate(MAX_PREFIX_COMPLETIONS);
+        // Like any unknown term, a prefix nothing starts with is ignored
+        if !completions.is_empty() {
+            let completed: BTreeSet<&str> = completions.iter()
+                .flat_map(|term| term_stats[*term].document_ids.iter().map(String::as_str))
+                .collect();
+            if tokens.iter().any(|token| term_stats.contains_key(*token)) {
+                matched_document_ids.retain(|document_id| completed.contains(document_id));
+            } else {
+                matched_document_ids = completed.into_iter().collect();
+            }
+            // Each document is scored by the completions it contains
+            tokens.extend(completions);
+            query_length += 1;
+        }
+    }
+    let match_duration = start_time.elapsed();
+    let (sorted_document_ids, scores, query_terms, sort_duration) = if opts.score {
+        score_and_sort_documents(source, documents, loaded_documents_shards, document_stats, loaded_document_stats_shards, term_stats, shard_count, &matched_document_ids, &tokens, query_length, opts)?
+    } else {
+        let mut sorted_document_ids = matched_document_ids;
+        sorted_document_ids.sort_unstable();
+        let scores = vec![0.0; sorted_document_ids.len()];
+        // Only worked out to be reported, nothing is scored with them
+        let query_terms = if opts.return_term_stats {
+            query_terms(document_count(source, shard_count)?, term_stats, &tokens, &opts.term_weights, opts.stop_word_weight)?
+        } else {
+            Vec::new()
+        };
+        (sorted_document_ids, scores, query_terms, Duration::default())
+    };
+    let (document_ids, scores) = sorted_document_ids.into_iter()
+        .zip(scores)
+        .filter(|(_, score)| !opts.score || !opts.drop_zero_score || *score != 0.0)
+        .map(|(document_id, score)| (document_id.to_string(), score))
+        .unzip();
+
+    // Reported with the IDF the documents were actually scored with
+    let mut term_stat_summaries = BTreeMap::new();
+    if opts.return_term_stats {
+        for query_term in query_terms.iter().filter(|query_term| term_stats.contains_key(query_term.token)) {
+            term_stat_summaries.insert(query_term.token.to_string(), TermStatSummary{
+                document_frequency: document_frequency(term_stats, query_term.token) as usize,
+                inverse_document_frequency: query_term.inverse_document_frequency,
+            });
+        }
+    }
+
+    Ok(Ranking {
+        document_ids,
+        scores,
+        match_duration,
+        sort_duration,
+        term_stats_shards: shard_ids.len(),
+        term_stats: term_stat_summaries,
+        query_tokens: tmp,
+    })
+}
+
+// Terms in `term_stats` starting with `prefix`, in term order
+fn terms_starting_with<'a>(term_stats: &'a TermStats, prefix: &str) -> Vec<&'a str> {
+    term_stats.range(prefix.to_string()..)
+        .map(|(term, _)| term.as_str())
+        .take_while(|term| term.starts_with(prefix))
+        .collect()
+}
+
+fn find_documents<'a>(term_stats: &'a TermStats, tokens: &[&str]) -> Result<Vec<&'a str>, Error> {
+    // None until the first term found in the index, an empty set after it
+    // means nothing matches every term
+    let mut document_ids_set: Option<BTreeSet<&str>> = None;
 
     for token in tokens {
         let term_stat = if let Some(term_stat) = term_stats.get(*token) {
@@ -260,103 +1346,342 @@ fn find_documents<'a>(term_stats: &'a TermStats, tokens: &[&str]) -> Result<(Vec
             continue;
         };
 
-        let ids: Vec<&'a str> = term_stat.document_ids.iter().map(String::as_str).collect();
-        if document_ids_set.len() == 0 {
-            document_ids_set = ids.iter().cloned().collect();
-        } else if document_ids_set.len() == 1 {
+        let ids = term_stat.document_ids.iter().map(String::as_str);
+        document_ids_set = Some(match document_ids_set {
+            None => ids.collect(),
+            Some(document_ids_set) => ids.filter(|id| document_ids_set.contains(id)).collect(),
+        });
+    }
+
+    let document_ids = document_ids_set.unwrap_or_default().into_iter().collect();
+
+    Ok(document_ids)
+}
+
+fn find_documents_sorted_merge<'a>(term_stats: &'a TermStats, tokens: &[&str]) -> Vec<&'a str> {
+    let mut posting_lists: Vec<&BTreeSet<String>> = tokens.iter()
+        .filter_map(|token| term_stats.get(*token))
+        .map(TermStat::get_document_id_set)
+        .collect();
+    posting_lists.sort_by_key(|document_ids| document_ids.len());
+
+    let mut posting_lists = posting_lists.into_iter();
+    let mut document_ids: Vec<&str> = match posting_lists.next() {
+        Some(document_ids) => document_ids.iter().map(String::as_str).collect(),
+        None => return Vec::new(),
+    };
+    for other_document_ids in posting_lists {
+        if document_ids.is_empty() {
             break;
-        } else {
-            let mut new_ids_set = HashSet::new();
-            for id in ids {
-                if let Some(x) = document_ids_set.take(id) {
-                    new_ids_set.insert(x);
-                }
-            }
-            document_ids_set = new_ids_set;
+        }
+        document_ids = intersect_sorted(document_ids, other_document_ids);
+    }
+
+    document_ids
+}
+
+fn intersect_sorted<'a>(document_ids: Vec<&'a str>, other_document_ids: &BTreeSet<String>) -> Vec<&'a str> {
+    let mut result = Vec::with_capacity(document_ids.len());
+    let mut others = other_document_ids.iter().map(String::as_str).peekable();
+
+    for document_id in document_ids {
+        while others.next_if(|other| *other < document_id).is_some() {}
+        match others.peek() {
+            Some(other) if *other == document_id => result.push(document_id),
+            Some(_) => {},
+            None => break,
         }
     }
 
-    let document_ids = document_ids_set.into_iter().collect();
-    let elapsed_time = start_time.elapsed();
+    result
+}
 
-    Ok((document_ids, elapsed_time))
+pub fn sort_documents<'a>(source: &ShardSource, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, usize>, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, usize>, term_stats: &'a TermStats, shard_count: usize, document_ids: &[&'a str], tokens: &[&'a str], opts: &SearchOptions) -> Result<(Vec<&'a str>, Vec<f64>, Duration), Error> {
+    let (document_ids, scores, _, duration) = score_and_sort_documents(source, documents, loaded_documents_shards, document_stats, loaded_document_stats_shards, term_stats, shard_count, document_ids, tokens, tokens.len(), opts)?;
+    Ok((document_ids, scores, duration))
 }
 
-pub fn sort_documents<'a>(index_name: &str, documents: &BTreeMap<DocumentID, Value>, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, bool>, term_stats: &'a TermStats, shard_count: usize, document_ids: &[&'a str], tokens: &[&'a str]) -> Result<(Vec<&'a str>, Vec<f64>, Duration), Error> {
+// Sorted document IDs, their scores, the query terms they were scored with
+// and how long it took
+type ScoredDocuments<'a> = (Vec<&'a str>, Vec<f64>, Vec<QueryTerm<'a>>, Duration);
+
+// Like `sort_documents` but also returns the query terms the documents were
+// scored with
+fn score_and_sort_documents<'a>(source: &ShardSource, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, usize>, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, usize>, term_stats: &'a TermStats, shard_count: usize, document_ids: &[&'a str], tokens: &[&'a str], query_length: usize, opts: &SearchOptions) -> Result<ScoredDocuments<'a>, Error> {
     let start_time = Instant::now();
-    let mut document_id_scores = Vec::with_capacity(document_ids.len());
+    let query_terms = query_terms(document_count(source, shard_count)?, term_stats, tokens, &opts.term_weights, opts.stop_word_weight)?;
+
+    let normalizer = match opts.score_normalization {
+        ScoreNormalization::Raw => 1.0,
+        ScoreNormalization::QueryLength => query_length as f64,
+        ScoreNormalization::SummedIdf => query_terms.iter()
+            .filter(|query_term| term_stats.contains_key(query_term.token))
+            .map(|query_term| query_term.inverse_document_frequency)
+            .sum(),
+    };
+    // Nothing to normalize by, e.g. every query term is unknown
+    let normalizer = if normalizer == 0.0 { 1.0 } else { normalizer };
 
-    for document_id in document_ids {
-        let score = calculate_score(index_name, documents, document_stats, loaded_document_stats_shards, term_stats, shard_count, &document_id, tokens)?;
-        document_id_scores.push((document_id, score));
+    let mut document_id_scores = if opts.parallel_scoring {
+        score_documents_parallel(source, document_stats, loaded_document_stats_shards, shard_count, document_ids, &query_terms, normalizer, opts.min_document_length)?
+    } else {
+        score_documents(source, document_stats, loaded_document_stats_shards, shard_count, document_ids, &query_terms, normalizer, opts.min_document_length)?
+    };
+
+    // Highest score first, then by document ID so the order is stable across runs
+    document_id_scores.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    // Equal scores are ordered by the tie breaker field before the ID, only
+    // the tied documents' sources are needed for it
+    if let Some((field, order)) = &opts.tie_breaker_field {
+        let tied_document_ids: Vec<&str> = document_id_scores.chunk_by(|a, b| a.1 == b.1)
+            .filter(|tied| tied.len() > 1)
+            .flat_map(|tied| tied.iter().map(|(document_id, _)| *document_id))
+            .collect();
+        let shard_ids: BTreeSet<ShardID> = tied_document_ids.iter().map(|document_id| calculate_shard_id(&source.id_normalization.normalize(document_id), shard_count as u32)).collect();
+        for shard_id in shard_ids {
+            load_documents_from_shard(source, documents, loaded_documents_shards, shard_id, shard_count)?;
+        }
+
+        // A document missing from its shard is treated as missing the field
+        let mut tie_breaker_values = BTreeMap::new();
+        for document_id in tied_document_ids {
+            let value = documents.get(source.id_normalization.normalize(document_id).as_ref())
+                .and_then(|document| source_field(document, field))
+                .filter(|value| !value.is_null());
+            if let Some(value) = value {
+                tie_breaker_values.insert(document_id, value);
+            }
+        }
+
+        for tied in document_id_scores.chunk_by_mut(|a, b| a.1 == b.1).filter(|tied| tied.len() > 1) {
+            tied.sort_by(|a, b| {
+                let tie_breaker = match (order, tie_breaker_values.get(a.0), tie_breaker_values.get(b.0)) {
+                    (Order::Ascending, Some(a), Some(b)) => compare_field_values(a, b),
+                    (Order::Descending, Some(a), Some(b)) => compare_field_values(b, a),
+                    // Documents missing the field come after the ones having it
+                    (_, Some(_), None) => Ordering::Less,
+                    (_, None, Some(_)) => Ordering::Greater,
+                    _ => Ordering::Equal,
+                };
+                tie_breaker.then_with(|| a.0.cmp(b.0))
+            });
+        }
     }
 
-    document_id_scores.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+    if let (Some(ratio), Some(&(_, top_score))) = (opts.top_score_ratio, document_id_scores.first()) {
+        document_id_scores.retain(|(_, score)| *score >= ratio * top_score);
+    }
     let (document_ids, scores) = document_id_scores.into_iter().unzip();
-    Ok((document_ids, scores, start_time.elapsed()))
+    Ok((document_ids, scores, query_terms, start_time.elapsed()))
 }
 
-fn fetch_hits(index_name: &str, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, bool>, shard_count: usize, document_ids: &[&str], scores: Vec<f64>, size: usize, from: usize) -> Result<Vec<Hit>, Error> {
-    let mut n = document_ids.len();
-    let mut hits = Vec::new();
+// A query term's weight and IDF, the parts of its score that are the same
+// for every matched document and so are only worked out once per search
+struct QueryTerm<'a> {
+    token: &'a str,
+    weight: f64,
+    inverse_document_frequency: f64,
+}
 
-    if size == 0 || from >= n {
-        return Ok(hits);
+fn query_terms<'a>(document_count: usize, term_stats: &TermStats, tokens: &[&'a str], term_weights: &BTreeMap<String, f64>, stop_word_weight: Option<(Language, f64)>) -> Result<Vec<QueryTerm<'a>>, Error> {
+    let default_weight = |token: &str| match stop_word_weight {
+        Some((language, weight)) if language.stop_words().contains(&token) => weight,
+        _ => 1.0,
+    };
+
+    tokens.iter()
+        .map(|token| Ok(QueryTerm {
+            token,
+            weight: term_weights.get(*token).copied().unwrap_or_else(|| default_weight(token)),
+            inverse_document_frequency: inverse_document_frequency(document_count, term_stats, token)?,
+        }))
+        .collect()
+}
+
+fn score_documents<'a>(source: &ShardSource, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, usize>, shard_count: usize, document_ids: &[&'a str], query_terms: &[QueryTerm], normalizer: f64, min_document_length: Option<usize>) -> Result<Vec<(&'a str, f64)>, Error> {
+    let mut document_id_scores = Vec::with_capacity(document_ids.len());
+
+    for &document_id in document_ids {
+        let score = calculate_score(source, document_stats, loaded_document_stats_shards, shard_count, document_id, query_terms)?;
+        let document_stat = fetch_document_stat(source, document_stats, loaded_document_stats_shards, document_id, shard_count)?;
+        let factor = document_factor(document_stat, min_document_length);
+        document_id_scores.push((document_id, score * factor / normalizer));
     }
-    if n > size {
-        n = size;
+
+    Ok(document_id_scores)
+}
+
+/// Scores like `score_documents` but spreads the documents over the rayon
+/// thread pool. The document stats shards are all loaded first as the
+/// scoring threads only read them.
+#[cfg(feature = "rayon")]
+fn score_documents_parallel<'a>(source: &ShardSource, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, usize>, shard_count: usize, document_ids: &[&'a str], query_terms: &[QueryTerm], normalizer: f64, min_document_length: Option<usize>) -> Result<Vec<(&'a str, f64)>, Error> {
+    use rayon::prelude::*;
+
+    let shard_ids: BTreeSet<ShardID> = document_ids.iter().map(|document_id| calculate_shard_id(document_id, shard_count as u32)).collect();
+    for shard_id in shard_ids {
+        load_document_stats_from_shard(source, document_stats, loaded_document_stats_shards, shard_id, shard_count)?;
     }
 
-    for (id, score) in document_ids.iter().cloned().zip(scores).skip(from).take(n) {
-        let source = fetch_document(index_name, documents, loaded_documents_shards, &id, shard_count)?;
-        hits.push(Hit{ id: id.to_string(), score, source });
+    let document_stats = &*document_stats;
+    let document_id_scores = document_ids.par_iter().map(|&document_id| {
+        let document_stat = document_stats.get(document_id);
+        let mut score = 0.0;
+        for query_term in query_terms {
+            let tf = document_stat.and_then(|document_stat| document_stat.term_frequency.get(query_term.token)).map_or(0.0, |n| *n as f64);
+            score += query_term.weight * tf * query_term.inverse_document_frequency;
+        }
+        let factor = document_factor(document_stat, min_document_length);
+        (document_id, score * factor / normalizer)
+    }).collect();
+
+    Ok(document_id_scores)
+}
+
+// What a document's summed term scores are multiplied by: its boost, and a
+// penalty for documents shorter than `min_document_length` so that one
+// term cannot dominate a thin document
+fn document_factor(document_stat: Option<&DocumentStat>, min_document_length: Option<usize>) -> f64 {
+    let document_stat = match document_stat {
+        Some(document_stat) => document_stat,
+        None => return 1.0,
+    };
+
+    let mut factor = document_stat.get_boost();
+    if let Some(min_document_length) = min_document_length {
+        let length = document_stat.get_length();
+        if length < min_document_length {
+            factor *= length as f64 / min_document_length as f64;
+        }
+    }
+    factor
+}
+
+// Without the rayon feature there is only the serial scoring
+#[cfg(not(feature = "rayon"))]
+fn score_documents_parallel<'a>(source: &ShardSource, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, usize>, shard_count: usize, document_ids: &[&'a str], query_terms: &[QueryTerm], normalizer: f64, min_document_length: Option<usize>) -> Result<Vec<(&'a str, f64)>, Error> {
+    score_documents(source, document_stats, loaded_document_stats_shards, shard_count, document_ids, query_terms, normalizer, min_document_length)
+}
+
+fn fetch_hits(source: &ShardSource, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, usize>, shard_count: usize, document_ids: &[DocumentID], scores: Vec<f64>, opts: &SearchOptions) -> Result<Vec<Hit>, Error> {
+    let (size, from) = (opts.size, opts.from);
+    let mut hits = Vec::new();
+
+    if size == 0 || from >= document_ids.len() {
+        return Ok(hits);
+    }
+    // The last page may hold fewer than `size` hits
+    let n = size.min(document_ids.len() - from);
+
+    let page: Vec<&str> = document_ids.iter().skip(from).take(n).map(String::as_str).collect();
+    let sources = fetch_documents(source, documents, loaded_documents_shards, &page, shard_count)?;
+    for ((id, score), mut source) in document_ids.iter().cloned().zip(scores).skip(from).zip(sources) {
+        if opts.flatten_source {
+            source = flatten_document(&source);
+        }
+        hits.push(Hit{ id, score, source });
     }
     
     Ok(hits)
 }
 
-pub fn fetch_document(index_name: &str, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, bool>, document_id: &str, shard_count: usize) -> Result<Value, Error> {
+pub fn fetch_document(source: &ShardSource, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, usize>, document_id: &str, shard_count: usize) -> Result<Value, Error> {
     if shard_count == 0 {
-        return Err(());
+        return Err(IndexError::NotLoaded);
     }
 
-    let shard_id = calculate_shard_id(document_id, shard_count as u32);
-    load_documents_from_shard(index_name, documents, loaded_documents_shards, shard_id)?;
+    let document_id = source.id_normalization.normalize(document_id);
+    let shard_id = calculate_shard_id(&document_id, shard_count as u32);
+    load_documents_from_shard(source, documents, loaded_documents_shards, shard_id, shard_count)?;
 
-    if let Some(document) = documents.get(document_id) {
+    if let Some(document) = documents.get(document_id.as_ref()) {
         Ok(document.clone())
     } else {
-        return Err(());
+        return Err(IndexError::DocumentNotFound(document_id.to_string()));
+    }
+}
+
+/// Fetches several documents, in the order of `document_ids`, loading each
+/// shard they live in only once.
+pub fn fetch_documents(source: &ShardSource, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, usize>, document_ids: &[&str], shard_count: usize) -> Result<Vec<Value>, Error> {
+    if shard_count == 0 {
+        return Err(IndexError::NotLoaded);
     }
+
+    let document_ids: Vec<Cow<str>> = document_ids.iter().map(|document_id| source.id_normalization.normalize(document_id)).collect();
+    let shard_ids: BTreeSet<ShardID> = document_ids.iter().map(|document_id| calculate_shard_id(document_id, shard_count as u32)).collect();
+    for shard_id in shard_ids {
+        load_documents_from_shard(source, documents, loaded_documents_shards, shard_id, shard_count)?;
+    }
+
+    document_ids.iter()
+        .map(|document_id| documents.get(document_id.as_ref()).cloned().ok_or_else(|| IndexError::DocumentNotFound(document_id.to_string())))
+        .collect()
 }
 
-fn load_documents_from_shard(index_name: &str, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, bool>, shard_id: ShardID) -> Result<(), Error> {
+fn load_documents_from_shard(source: &ShardSource, documents: &mut BTreeMap<DocumentID, Value>, loaded_documents_shards: &mut BTreeMap<usize, usize>, shard_id: ShardID, shard_count: usize) -> Result<(), Error> {
     if loaded_documents_shards.contains_key(&(shard_id as usize)) {
         return Ok(())
     }
 
-    let file_path = format!("{}/{}/{}", index_name, &shard_id, DOCUMENTS_FILE_EXTENSION);
-    let file = File::open(&file_path).unwrap();
-    load_documents_from_reader(documents, file)?;
-    loaded_documents_shards.insert(shard_id as usize, true);
+    let mut file = CountingReader::new(source.open_shard(shard_id, DOCUMENTS_FILE_EXTENSION)?);
+    load_documents_from_reader(documents, &mut file, source.duplicate_policy, source.id_normalization, shard_id, shard_count)?;
+    loaded_documents_shards.insert(shard_id as usize, file.bytes);
 
     Ok(())
 }
 
-fn load_documents_from_reader<T: Read>(documents: &mut BTreeMap<DocumentID, Value>, r: T) -> Result<(), Error> {
+fn load_documents_from_reader<T: Read>(documents: &mut BTreeMap<DocumentID, Value>, r: T, duplicate_policy: DuplicatePolicy, id_normalization: IdNormalization, shard_id: ShardID, shard_count: usize) -> Result<(), Error> {
     let mut csvr = csv::Reader::from_reader(r);
     let headers = csvr.headers().unwrap().clone();
+    let mut shard_document_ids = BTreeSet::new();
 
     while let Some(result) = csvr.records().next() {
         let record = result.unwrap();
-        let document_id = record.get(0).unwrap();
+        let document_id = id_normalization.normalize(record.get(0).unwrap());
+        let document_id = document_id.as_ref();
         let document = document_from_record(&headers, &record);
-        documents.insert(document_id.to_string(), document);
+
+        match duplicate_source(documents.contains_key(document_id), &mut shard_document_ids, document_id, duplicate_policy, shard_id, shard_count)? {
+            Some(DuplicateSource::Shard) if duplicate_policy == DuplicatePolicy::Merge => merge_documents(documents.get_mut(document_id).unwrap(), document),
+            Some(_) => {},
+            None => {
+                documents.insert(document_id.to_string(), document);
+            },
+        }
     }
 
     Ok(())
 }
 
+// Where the copy already loaded for a document being loaded again came from
+enum DuplicateSource {
+    Shard,      // Earlier in the same shard, merged or skipped by the duplicate policy
+    OtherShard, // Another shard, kept unless it's the document's home shard being loaded
+}
+
+// Works out whether a row of shard `shard_id` is a duplicate, dropping the
+// copy loaded from another shard when `shard_id` is the document's home shard
+// so that copy always wins whatever order the shards are loaded in
+fn duplicate_source(loaded: bool, shard_document_ids: &mut BTreeSet<String>, document_id: &str, duplicate_policy: DuplicatePolicy, shard_id: ShardID, shard_count: usize) -> Result<Option<DuplicateSource>, Error> {
+    if !loaded {
+        shard_document_ids.insert(document_id.to_string());
+        return Ok(None);
+    }
+    if duplicate_policy == DuplicatePolicy::Error {
+        return Err(IndexError::DuplicateDocument(document_id.to_string()));
+    }
+    if shard_document_ids.contains(document_id) {
+        return Ok(Some(DuplicateSource::Shard));
+    }
+    if calculate_shard_id(document_id, shard_count as u32) == shard_id {
+        shard_document_ids.insert(document_id.to_string());
+        return Ok(None);
+    }
+    Ok(Some(DuplicateSource::OtherShard))
+}
+
 fn document_from_record(headers: &StringRecord, record: &StringRecord) -> Value {
     let mut document = Value::Object(Map::new());
 
@@ -367,73 +1692,155 @@ fn document_from_record(headers: &StringRecord, record: &StringRecord) -> Value
     document
 }
 
+fn collect_string_values<'a>(value: &'a Value, values: &mut Vec<&'a str>) {
+    match value {
+        Value::String(s) => values.push(s),
+        Value::Array(array) => array.iter().for_each(|value| collect_string_values(value, values)),
+        Value::Object(object) => object.values().for_each(|value| collect_string_values(value, values)),
+        _ => {},
+    }
+}
+
+// Counts the bytes read through it, the size of a loaded shard's file stands
+// in for the memory its parsed entries take up
+struct CountingReader<R> {
+    inner: R,
+    bytes: usize,
+}
+
+impl<R: Read> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, bytes: 0 }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes += n;
+        Ok(n)
+    }
+}
+
+fn merge_documents(existing: &mut Value, document: Value) {
+    match (existing, document) {
+        (Value::Object(existing), Value::Object(document)) => existing.extend(document),
+        (existing, document) => *existing = document,
+    }
+}
+
+fn flatten_document(document: &Value) -> Value {
+    let mut flattened = Map::new();
+    flatten_value(&mut flattened, "", document);
+    Value::Object(flattened)
+}
+
+fn flatten_value(flattened: &mut Map<String, Value>, field_name: &str, value: &Value) {
+    if let Value::Object(object) = value {
+        for (field, value) in object.iter() {
+            if field_name.is_empty() {
+                flatten_value(flattened, field, value);
+            } else {
+                let with_parent = format!("{}.{}", field_name, field);
+                flatten_value(flattened, &with_parent, value);
+            }
+        }
+    } else {
+        flattened.insert(field_name.to_string(), value.clone());
+    }
+}
+
 fn calculate_shard_id(s: &str, shard_count: u32) -> ShardID {
     const Q: ShardID = 123456789;
 
-    let mut result = 0;
+    // Wrapping, so debug builds route like release ones instead of panicking
+    let mut result: ShardID = 0;
     for c in s.chars() {
-        result += Q + (c as u32) * (c as u32);
+        result = result.wrapping_add(Q.wrapping_add((c as u32).wrapping_mul(c as u32)));
     }
-    result *= Q;
+    result = result.wrapping_mul(Q);
 
     let shard_id = result % shard_count;
     shard_id
 }
 
-fn load_document_stats_from_shard(index_name: &str, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, bool>, shard_id: ShardID) -> Result<(), Error> {
+fn load_document_stats_from_shard(source: &ShardSource, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, usize>, shard_id: ShardID, shard_count: usize) -> Result<(), Error> {
     if loaded_document_stats_shards.contains_key(&(shard_id as usize)) {
         return Ok(())
     }
 
-    let file_path = format!("{}/{}/{}", index_name, &shard_id, DOCUMENT_STATS_FILE_EXTENSION);
-    let file = File::open(&file_path).unwrap();
-    load_document_stats_from_reader(document_stats, file)?;
-    loaded_document_stats_shards.insert(shard_id as usize, true);
+    let bytes = match source.open_shard(shard_id, DOCUMENT_STATS_FILE_EXTENSION) {
+        Ok(file) => {
+            let mut file = CountingReader::new(file);
+            load_document_stats_from_reader(document_stats, &mut file, source.duplicate_policy, source.id_normalization, shard_id, shard_count, &source.shard_path(shard_id, DOCUMENT_STATS_FILE_EXTENSION))?;
+            file.bytes
+        },
+        // Documents in the shard are still matched but score 0.0
+        Err(IndexError::FileNotFound(_)) if source.missing_document_stats == MissingShardPolicy::Ignore => 0,
+        Err(err) => return Err(err),
+    };
+    loaded_document_stats_shards.insert(shard_id as usize, bytes);
 
     Ok(())
 }
 
-fn load_document_stats_from_reader<T: Read>(document_stats: &mut BTreeMap<DocumentID, DocumentStat>, r: T) -> Result<(), Error> {
+fn load_document_stats_from_reader<T: Read>(document_stats: &mut BTreeMap<DocumentID, DocumentStat>, r: T, duplicate_policy: DuplicatePolicy, id_normalization: IdNormalization, shard_id: ShardID, shard_count: usize, file_path: &str) -> Result<(), Error> {
     let mut csvr = csv::Reader::from_reader(r);
+    let mut shard_document_ids = BTreeSet::new();
 
     for result in csvr.records() {
         let record = result.unwrap();
-        let document_id = record.get(0).unwrap();
-        let tfs: Vec<&str> = record.get(1).unwrap().split(' ').collect();
+        let document_id = id_normalization.normalize(record.get(0).unwrap());
+        let document_id = document_id.as_ref();
+
+        match duplicate_source(document_stats.contains_key(document_id), &mut shard_document_ids, document_id, duplicate_policy, shard_id, shard_count)? {
+            Some(DuplicateSource::Shard) if duplicate_policy == DuplicatePolicy::Merge => {},
+            Some(_) => continue,
+            // A document without any indexed terms, e.g. one with only numeric
+            // fields, has an empty row but still gets a stat so it scores 0.0
+            None => {
+                document_stats.insert(document_id.to_string(), DocumentStat::new());
+            },
+        }
+
+        let tfs: Vec<&str> = record.get(1).unwrap().split(' ').filter(|v| !v.is_empty()).collect();
         for v in tfs {
             let vv: Vec<&str> = v.split(':').collect();
             let term = vv.get(0).unwrap();
             let frequency = vv.get(1).unwrap();
 
-            if !document_stats.contains_key(document_id) {
-                document_stats.insert(document_id.to_string(), DocumentStat::new());
-            }
-
             if let Some(document_stat) = document_stats.get_mut(document_id) {
                 let frequency: usize = frequency.parse().unwrap();
                 document_stat.get_term_frequency_mut().insert(term.to_string(), frequency);
             }
         }
+
+        // Optional third column holding the document's boost
+        if let Some(boost) = record.get(2).filter(|boost| !boost.is_empty()) {
+            let boost = boost.parse().map_err(|_| IndexError::InvalidFile(file_path.to_string()))?;
+            if let Some(document_stat) = document_stats.get_mut(document_id) {
+                document_stat.set_boost(boost);
+            }
+        }
     }
 
     Ok(())
 }
 
-fn fetch_document_stat<'a>(index_name: &str, document_stats: &'a mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, bool>, document_id: &str, shard_count: usize) -> Result<Option<&'a DocumentStat>, Error> {
-    if !document_stats.contains_key(document_id) {
-        let shard_id = calculate_shard_id(&document_id, shard_count as u32);
-        load_document_stats_from_shard(index_name, document_stats, loaded_document_stats_shards, shard_id)?;
-    }
+fn fetch_document_stat<'a>(source: &ShardSource, document_stats: &'a mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, usize>, document_id: &str, shard_count: usize) -> Result<Option<&'a DocumentStat>, Error> {
+    // Even with a copy loaded from another shard, as the home shard's wins
+    let shard_id = calculate_shard_id(document_id, shard_count as u32);
+    load_document_stats_from_shard(source, document_stats, loaded_document_stats_shards, shard_id, shard_count)?;
     Ok(document_stats.get(document_id))
 }
 
-pub fn fetch_term_stat<'a>(index_name: &str, term_stats: &'a mut TermStats, loaded_term_stats_shards: &mut BTreeMap<usize, bool>, token: &str, shard_count: usize) -> Result<Option<&'a TermStat>, Error> {
+pub fn fetch_term_stat<'a>(source: &ShardSource, term_stats: &'a mut TermStats, loaded_term_stats_shards: &mut BTreeMap<usize, usize>, token: &str, shard_count: usize) -> Result<Option<&'a TermStat>, Error> {
     if term_stats.contains_key(token) {
         let term_stat = term_stats.get(token).unwrap();
         Ok(Some(term_stat))
     } else {
         let shard_id = calculate_shard_id(token, shard_count as u32);
-        load_term_stats_from_shard(index_name, term_stats, loaded_term_stats_shards, shard_id)?;
+        load_term_stats_from_shard(source, term_stats, loaded_term_stats_shards, shard_id)?;
 
         if let Some(term_stat) = term_stats.get(token) {
             return Ok(Some(term_stat));
@@ -443,56 +1850,55 @@ pub fn fetch_term_stat<'a>(index_name: &str, term_stats: &'a mut TermStats, load
     }
 }
 
-fn load_term_stats_from_shard(index_name: &str, term_stats: &mut TermStatsRef, loaded_term_stats_shards: &mut BTreeMap<usize, bool>, shard_id: ShardID) -> Result<(), Error> {
+fn load_term_stats_from_shard(source: &ShardSource, term_stats: &mut TermStatsRef, loaded_term_stats_shards: &mut BTreeMap<usize, usize>, shard_id: ShardID) -> Result<(), Error> {
     if loaded_term_stats_shards.contains_key(&(shard_id as usize)) {
         return Ok(())
     }
 
-    let file_path = format!("{}/{}/{}", index_name, &shard_id, TERM_STATS_FILE_EXTENSION);
-    let file = File::open(&file_path).unwrap();
-    load_term_stats_from_reader(term_stats, file)?;
+    let mut file = CountingReader::new(source.open_shard(shard_id, TERM_STATS_FILE_EXTENSION)?);
+    load_term_stats_from_reader(term_stats, &mut file, source.id_normalization)?;
 
-    loaded_term_stats_shards.insert(shard_id as usize, true);
+    loaded_term_stats_shards.insert(shard_id as usize, file.bytes);
 
     Ok(())
 }
 
-fn load_term_stats_from_reader<T: Read>(term_stats: &mut TermStatsRef, r: T) -> Result<(), Error> {
+fn load_term_stats_from_reader<T: Read>(term_stats: &mut TermStatsRef, r: T, id_normalization: IdNormalization) -> Result<(), Error> {
     let mut csvr = csv::Reader::from_reader(r);
 
     for result in csvr.records() {
         let record = result.unwrap();
         let term = &record[0];
-        let document_ids = record[1].split(" ").map(String::from).collect();
+        let document_ids = record[1].split(" ").map(|document_id| id_normalization.normalize(document_id).into_owned()).collect();
         insert_term_stats_document_ids(term_stats, term, document_ids);
     }
 
     Ok(())
 }
 
-fn insert_term_stats_document_ids(term_stats: &mut TermStatsRef, term: &str, document_ids: Vec<String>) {
+fn insert_term_stats_document_ids(term_stats: &mut TermStatsRef, term: &str, document_ids: BTreeSet<String>) {
     let mut term_stat = if let Some(term_stat) = term_stats.get_mut(term) {
         term_stat.clone()
     } else {
         TermStat::new()
     };
-    term_stat.get_document_ids_mut().extend(document_ids);
+    term_stat.get_document_id_set_mut().extend(document_ids);
     term_stats.insert(term.to_string(), term_stat.clone());
 }
 
-fn calculate_score(index_name: &str, documents: &BTreeMap<DocumentID, Value>, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, bool>, term_stats: &TermStatsRef, shard_count: usize, document_id: &str, tokens: &[&str]) -> Result<f64, Error> {
+fn calculate_score(source: &ShardSource, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, usize>, shard_count: usize, document_id: &str, query_terms: &[QueryTerm]) -> Result<f64, Error> {
     let mut score = 0.0;
 
-    for token in tokens {
-        let tf = term_frequency(index_name, document_stats, loaded_document_stats_shards, document_id, token, shard_count)?;
-        score += tf * inverse_document_frequency(documents, term_stats, token)?;
+    for query_term in query_terms {
+        let tf = term_frequency(source, document_stats, loaded_document_stats_shards, document_id, query_term.token, shard_count)?;
+        score += query_term.weight * tf * query_term.inverse_document_frequency;
     }
 
     Ok(score)
 }
 
-fn term_frequency(index_name: &str, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, bool>, document_id: &str, token: &str, shard_count: usize) -> Result<f64, Error> {
-    let document_stat = if let Some(document_stat) = fetch_document_stat(index_name, document_stats, loaded_document_stats_shards, document_id, shard_count)? {
+fn term_frequency(source: &ShardSource, document_stats: &mut BTreeMap<DocumentID, DocumentStat>, loaded_document_stats_shards: &mut BTreeMap<usize, usize>, document_id: &str, token: &str, shard_count: usize) -> Result<f64, Error> {
+    let document_stat = if let Some(document_stat) = fetch_document_stat(source, document_stats, loaded_document_stats_shards, document_id, shard_count)? {
         document_stat
     } else {
         return Ok(0.0);
@@ -505,11 +1911,51 @@ fn term_frequency(index_name: &str, document_stats: &mut BTreeMap<DocumentID, Do
     Ok(term_frequency)
 }
 
-fn inverse_document_frequency(documents: &BTreeMap<DocumentID, Value>, term_stats: &TermStatsRef, token: &str) -> Result<f64, Error> {
-    let frequency = (documents.len() as f64) / document_frequency(term_stats, token);
-    Ok(frequency.log10())
+// The number of documents IDF is worked out with. Unless the caller set the
+// total or the index came with one, the distinct IDs of every document stats
+// shard are counted once. The loaded documents can't be used as they
+// undercount until every shard is loaded.
+fn document_count(source: &ShardSource, shard_count: usize) -> Result<usize, Error> {
+    if let Some(total_documents) = source.total_documents {
+        return Ok(total_documents);
+    }
+    if let Some(stored_documents) = source.stored_documents {
+        return Ok(stored_documents);
+    }
+    if let Some(counted_documents) = source.counted_documents.get() {
+        return Ok(*counted_documents);
+    }
+
+    let mut document_ids = BTreeSet::new();
+    for shard_id in 0..shard_count as ShardID {
+        // A missing document stats shard may be ignored, its documents still count
+        let file = match source.open_shard(shard_id, DOCUMENT_STATS_FILE_EXTENSION) {
+            Err(IndexError::FileNotFound(_)) if source.missing_document_stats == MissingShardPolicy::Ignore => source.open_shard(shard_id, DOCUMENTS_FILE_EXTENSION)?,
+            file => file?,
+        };
+        for result in csv::Reader::from_reader(file).records() {
+            let record = result.map_err(io::Error::from)?;
+            if let Some(document_id) = record.get(0) {
+                document_ids.insert(source.id_normalization.normalize(document_id).into_owned());
+            }
+        }
+    }
+
+    Ok(*source.counted_documents.get_or_init(|| document_ids.len()))
+}
+
+fn inverse_document_frequency(document_count: usize, term_stats: &TermStatsRef, token: &str) -> Result<f64, Error> {
+    let document_frequency = document_frequency(term_stats, token);
+    if document_frequency == 0.0 {
+        return Ok(0.0);
+    }
+
+    // A total set too low can be below the term's document frequency. Clamp
+    // instead of letting the IDF go negative and invert the ranking.
+    let frequency = (document_count as f64) / document_frequency;
+    Ok(frequency.log10().max(0.0))
 }
 
 fn document_frequency(term_stats: &TermStatsRef, token: &str) -> f64 {
-    term_stats[token].document_ids.len() as f64
+    term_stats.get(token).map_or(0.0, |term_stat| term_stat.document_ids.len() as f64)
 }
\ No newline at end of file
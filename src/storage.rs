@@ -0,0 +1,204 @@
+#[cfg(any(feature = "zip", feature = "tar"))]
+use std::collections::BTreeMap;
+use std::fs::File;
+#[cfg(any(feature = "zip", feature = "tar"))]
+use std::io::Cursor;
+use std::io::{ErrorKind, Read};
+#[cfg(feature = "zip")]
+use std::io::Seek;
+
+use crate::{Error, IndexError};
+
+/// Source of the shard files making up an index. Paths are the same logical
+/// `{name}/{shard}/{ext}` paths regardless of where the files actually live.
+/// Storages are `Send` and `Sync` so that an `Index` can be moved to and
+/// shared between threads.
+pub trait Storage: Send + Sync {
+    fn open(&self, path: &str) -> Result<Box<dyn Read + '_>, Error>;
+}
+
+impl Default for Box<dyn Storage> {
+    fn default() -> Self {
+        Box::new(FileStorage)
+    }
+}
+
+/// Reads shard files from the local filesystem.
+pub struct FileStorage;
+
+impl Storage for FileStorage {
+    fn open(&self, path: &str) -> Result<Box<dyn Read + '_>, Error> {
+        let file = File::open(path).map_err(|err| match err.kind() {
+            ErrorKind::NotFound => IndexError::FileNotFound(path.to_string()),
+            _ => IndexError::Io(err),
+        })?;
+        Ok(Box::new(file))
+    }
+}
+
+/// Serves shard files compiled into the binary, so an index can be searched
+/// without any filesystem access. Each entry pairs a logical path with the
+/// file's bytes, typically from `include_bytes!`:
+///
+/// ```ignore
+/// static FILES: &[(&str, &[u8])] = &[
+///     ("index/shard_count", include_bytes!("../index/shard_count")),
+///     ("index/0/dcs", include_bytes!("../index/0/dcs")),
+///     // ...
+/// ];
+/// let index = Index::load_with_storage("index", EmbeddedStorage::new(FILES))?;
+/// ```
+///
+/// With the `include_dir` feature a whole directory can be embedded instead,
+/// with paths relative to it:
+///
+/// ```ignore
+/// static DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/indexes");
+/// let index = Index::load_with_storage("index", EmbeddedStorage::from_dir(&DIR))?;
+/// ```
+pub struct EmbeddedStorage {
+    files: EmbeddedFiles,
+}
+
+enum EmbeddedFiles {
+    Slice(&'static [(&'static str, &'static [u8])]),
+    #[cfg(feature = "include_dir")]
+    Dir(&'static include_dir::Dir<'static>),
+}
+
+impl EmbeddedStorage {
+    pub fn new(files: &'static [(&'static str, &'static [u8])]) -> Self {
+        Self { files: EmbeddedFiles::Slice(files) }
+    }
+
+    #[cfg(feature = "include_dir")]
+    pub fn from_dir(dir: &'static include_dir::Dir<'static>) -> Self {
+        Self { files: EmbeddedFiles::Dir(dir) }
+    }
+}
+
+impl Storage for EmbeddedStorage {
+    fn open(&self, path: &str) -> Result<Box<dyn Read + '_>, Error> {
+        let path = path.trim_start_matches("./");
+        let bytes = match &self.files {
+            EmbeddedFiles::Slice(files) => files.iter().find(|(file_path, _)| *file_path == path).map(|(_, bytes)| *bytes),
+            #[cfg(feature = "include_dir")]
+            EmbeddedFiles::Dir(dir) => dir.get_file(path).map(include_dir::File::contents),
+        };
+        match bytes {
+            Some(bytes) => Ok(Box::new(bytes)),
+            None => Err(IndexError::FileNotFound(path.to_string())),
+        }
+    }
+}
+
+/// Reads shard files packaged inside a single `.zip` or `.tar` archive. The
+/// archive entries are read into memory once so nothing is extracted to disk.
+#[cfg(any(feature = "zip", feature = "tar"))]
+pub struct ArchiveStorage {
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+#[cfg(any(feature = "zip", feature = "tar"))]
+impl ArchiveStorage {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        #[cfg(feature = "zip")]
+        if path.ends_with(".zip") {
+            return Self::from_zip(file);
+        }
+        #[cfg(feature = "tar")]
+        if path.ends_with(".tar") {
+            return Self::from_tar(file);
+        }
+        Err(IndexError::InvalidArchive)
+    }
+
+    #[cfg(feature = "zip")]
+    pub fn from_zip<T: Read + Seek>(r: T) -> Result<Self, Error> {
+        let mut archive = zip::ZipArchive::new(r).map_err(|_| IndexError::InvalidArchive)?;
+        let mut files = BTreeMap::new();
+
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).map_err(|_| IndexError::InvalidArchive)?;
+            if entry.is_dir() {
+                continue;
+            }
+            let name = entry.name().to_string();
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes)?;
+            files.insert(name, bytes);
+        }
+
+        Ok(Self { files })
+    }
+
+    #[cfg(feature = "tar")]
+    pub fn from_tar<T: Read>(r: T) -> Result<Self, Error> {
+        let mut archive = tar::Archive::new(r);
+        let mut files = BTreeMap::new();
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry.path()?.to_string_lossy().to_string();
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes)?;
+            files.insert(name, bytes);
+        }
+
+        Ok(Self { files })
+    }
+}
+
+#[cfg(any(feature = "zip", feature = "tar"))]
+impl Storage for ArchiveStorage {
+    fn open(&self, path: &str) -> Result<Box<dyn Read + '_>, Error> {
+        let path = path.trim_start_matches("./");
+        if let Some(bytes) = self.files.get(path) {
+            Ok(Box::new(Cursor::new(bytes.as_slice())))
+        } else {
+            Err(IndexError::FileNotFound(path.to_string()))
+        }
+    }
+}
+
+/// Fetches shard files over HTTP, with the logical path being the URL, so
+/// an index named `https://host/index` reads `https://host/index/0/dcs`.
+#[cfg(feature = "http")]
+pub struct HttpStorage {
+    client: reqwest::blocking::Client,
+}
+
+#[cfg(feature = "http")]
+impl HttpStorage {
+    pub fn new() -> Self {
+        Self::with_client(reqwest::blocking::Client::new())
+    }
+
+    /// Uses a client configured by the caller, e.g. with timeouts or headers.
+    pub fn with_client(client: reqwest::blocking::Client) -> Self {
+        Self { client }
+    }
+}
+
+#[cfg(feature = "http")]
+impl Default for HttpStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "http")]
+impl Storage for HttpStorage {
+    fn open(&self, path: &str) -> Result<Box<dyn Read + '_>, Error> {
+        let to_io_error = |err: reqwest::Error| IndexError::Io(std::io::Error::other(err));
+        let response = self.client.get(path).send().map_err(to_io_error)?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(IndexError::FileNotFound(path.to_string()));
+        }
+        Ok(Box::new(response.error_for_status().map_err(to_io_error)?))
+    }
+}
@@ -0,0 +1,1723 @@
+use super::*;
+
+use std::fs;
+use std::path::Path;
+
+use tempfile::TempDir;
+
+// ID, fields and boost of a fixture document
+type FixtureDocument = (String, Vec<(String, String)>, Option<f64>);
+
+// Documents to write as a test index, analyzed and routed to their shards
+// the same way the index reads them back
+struct Fixture {
+    shard_count: u32,
+    term_shard_count: Option<u32>,
+    analyzer: Analyzer,
+    documents: Vec<FixtureDocument>,
+}
+
+impl Fixture {
+    fn new(shard_count: u32) -> Self {
+        Self { shard_count, term_shard_count: None, analyzer: Analyzer::default(), documents: Vec::new() }
+    }
+
+    // A document with a single `body` field
+    fn document(self, document_id: &str, body: &str) -> Self {
+        self.document_with_fields(document_id, &[("body", body)])
+    }
+
+    fn document_with_fields(mut self, document_id: &str, fields: &[(&str, &str)]) -> Self {
+        let fields = fields.iter().map(|(field, value)| (field.to_string(), value.to_string())).collect();
+        self.documents.push((document_id.to_string(), fields, None));
+        self
+    }
+
+    // Spreads the terms over a different number of shards than the documents
+    fn term_shard_count(mut self, term_shard_count: u32) -> Self {
+        self.term_shard_count = Some(term_shard_count);
+        self
+    }
+
+    // Analyzes the documents with `analyzer` instead of the default one, the
+    // index searching them needs a matching analyzer
+    fn analyzer(mut self, analyzer: Analyzer) -> Self {
+        self.analyzer = analyzer;
+        self
+    }
+
+    // Sets the boost of the last added document
+    fn boost(mut self, boost: f64) -> Self {
+        self.documents.last_mut().unwrap().2 = Some(boost);
+        self
+    }
+
+    // Writes the index to `dir` and returns its name
+    fn write(&self, dir: &Path) -> String {
+        let index_name = dir.join("index").to_str().unwrap().to_string();
+        let term_shard_count = self.term_shard_count.unwrap_or(self.shard_count);
+
+        let mut fields: Vec<&str> = Vec::new();
+        for (_, document_fields, _) in &self.documents {
+            for (field, _) in document_fields {
+                if !fields.contains(&field.as_str()) {
+                    fields.push(field);
+                }
+            }
+        }
+
+        let mut documents: BTreeMap<ShardID, Vec<Vec<String>>> = BTreeMap::new();
+        let mut document_stats: BTreeMap<ShardID, Vec<Vec<String>>> = BTreeMap::new();
+        let mut term_stats: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for (document_id, document_fields, boost) in &self.documents {
+            let shard_id = calculate_shard_id(document_id, self.shard_count);
+
+            let mut row = vec![document_id.clone()];
+            for field in &fields {
+                let value = document_fields.iter().find(|(name, _)| name == field).map_or("", |(_, value)| value.as_str());
+                row.push(value.to_string());
+            }
+            documents.entry(shard_id).or_default().push(row);
+
+            let mut term_frequency: BTreeMap<String, usize> = BTreeMap::new();
+            for (_, value) in document_fields {
+                for token in self.analyzer.analyze(value).into_iter().filter(|token| !token.is_empty()) {
+                    term_stats.entry(token.clone()).or_default().insert(document_id.clone());
+                    *term_frequency.entry(token).or_insert(0) += 1;
+                }
+            }
+            let frequencies: Vec<String> = term_frequency.iter().map(|(term, frequency)| format!("{}:{}", term, frequency)).collect();
+            let boost = boost.map_or(String::new(), |boost| boost.to_string());
+            document_stats.entry(shard_id).or_default().push(vec![document_id.clone(), frequencies.join(" "), boost]);
+        }
+
+        let mut header = vec!["id"];
+        header.extend(&fields);
+        for shard_id in 0..self.shard_count {
+            fs::create_dir_all(format!("{}/{}", index_name, shard_id)).unwrap();
+            write_csv(&format!("{}/{}/dcs", index_name, shard_id), &header, documents.get(&shard_id));
+            write_csv(&format!("{}/{}/dst", index_name, shard_id), &["id", "terms", "boost"], document_stats.get(&shard_id));
+        }
+
+        let mut term_rows: BTreeMap<ShardID, Vec<Vec<String>>> = BTreeMap::new();
+        for (term, document_ids) in term_stats {
+            let document_ids: Vec<String> = document_ids.into_iter().collect();
+            term_rows.entry(calculate_shard_id(&term, term_shard_count)).or_default().push(vec![term, document_ids.join(" ")]);
+        }
+        for shard_id in 0..term_shard_count {
+            fs::create_dir_all(format!("{}/{}", index_name, shard_id)).unwrap();
+            write_csv(&format!("{}/{}/tst", index_name, shard_id), &["term", "ids"], term_rows.get(&shard_id));
+        }
+
+        fs::write(format!("{}/shard_count", index_name), self.shard_count.to_string()).unwrap();
+        if let Some(term_shard_count) = self.term_shard_count {
+            fs::write(format!("{}/term_shard_count", index_name), term_shard_count.to_string()).unwrap();
+        }
+        index_name
+    }
+
+    // Writes the index to a new temporary directory and loads it, the
+    // directory is removed once dropped
+    fn load(&self) -> (TempDir, Index) {
+        let dir = tempfile::tempdir().unwrap();
+        let index = Index::load(&self.write(dir.path())).unwrap();
+        (dir, index)
+    }
+}
+
+fn write_csv(path: &str, header: &[&str], rows: Option<&Vec<Vec<String>>>) {
+    let mut csvw = csv::Writer::from_path(path).unwrap();
+    csvw.write_record(header).unwrap();
+    for row in rows.into_iter().flatten() {
+        csvw.write_record(row).unwrap();
+    }
+    csvw.flush().unwrap();
+}
+
+// The documents every test searches unless it needs something special
+fn lunar_fixture() -> Fixture {
+    Fixture::new(3)
+        .document("1", "Lunar New Year")
+        .document("2", "New moon tonight")
+        .document("3", "Harvest moon festival")
+        .document("4", "The year of the moon")
+        .document("5", "A new year, a new start")
+        .document("6", "Solar eclipse")
+}
+
+fn hit_ids(result: &SearchResult) -> Vec<&str> {
+    result.hits.iter().map(|hit| hit.id.as_str()).collect()
+}
+
+fn hit_scores(result: &SearchResult) -> Vec<f64> {
+    result.hits.iter().map(|hit| hit.score).collect()
+}
+
+#[test]
+fn idf_counts_documents_in_unloaded_shards() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let first = index.search("lunar").unwrap();
+    let second = index.search("lunar").unwrap();
+
+    assert_eq!(hit_ids(&first), ["1"]);
+    assert_eq!(hit_scores(&first), [(6.0f64).log10()]);
+    assert_eq!(hit_scores(&first), hit_scores(&second));
+}
+
+#[test]
+fn idf_is_never_negative_on_a_cold_index() {
+    let (_dir, mut index) = Fixture::new(4)
+        .document("1", "moon")
+        .document("2", "moon")
+        .document("3", "moon sun")
+        .load();
+
+    let result = index.search("moon").unwrap();
+    assert_eq!(result.len(), 3);
+    assert!(hit_scores(&result).iter().all(|score| *score == 0.0));
+}
+
+#[test]
+fn idf_uses_the_document_count_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().write(dir.path());
+    fs::write(format!("{}/document_count", index_name), "600").unwrap();
+
+    let mut index = Index::load(&index_name).unwrap();
+    assert_eq!(hit_scores(&index.search("lunar").unwrap()), [(600.0f64).log10()]);
+
+    // A total set by the caller takes precedence
+    index.set_total_documents(Some(60));
+    assert_eq!(hit_scores(&index.search("lunar").unwrap()), [(60.0f64).log10()]);
+}
+
+#[test]
+fn idf_fails_when_documents_cannot_be_counted() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().write(dir.path());
+    fs::remove_file(format!("{}/0/dst", index_name)).unwrap();
+    fs::remove_file(format!("{}/0/dcs", index_name)).unwrap();
+
+    let mut index = Index::load(&index_name).unwrap();
+    index.set_missing_document_stats_policy(MissingShardPolicy::Ignore);
+    assert!(matches!(index.search("lunar"), Err(IndexError::FileNotFound(_))));
+}
+
+#[test]
+fn drop_zero_score_keeps_scored_hits_on_a_cold_index() {
+    let fixture = Fixture::new(2)
+        .document("1", "common rare")
+        .document("2", "common")
+        .document("3", "common");
+
+    let (_dir, mut index) = fixture.load();
+    let result = index.search("common").unwrap();
+    assert_eq!(hit_ids(&result), ["1", "2", "3"]);
+
+    // "common" is in every document, so only "rare" scores
+    let (_dir, mut index) = fixture.load();
+    let opts = SearchOptions{ drop_zero_score: true, ..SearchOptions::default() };
+    let result = index.search_with_options("common", opts).unwrap();
+    assert_eq!(result.len(), 0);
+
+    let (_dir, mut index) = fixture.load();
+    let opts = SearchOptions{ drop_zero_score: true, ..SearchOptions::default() };
+    let result = index.search_with_options("common rare", opts).unwrap();
+    assert_eq!(hit_ids(&result), ["1"]);
+    assert!(result.hits[0].score > 0.0);
+}
+
+fn prefix_opts() -> SearchOptions {
+    SearchOptions{ prefix_last_token: true, return_term_stats: true, ..SearchOptions::default() }
+}
+
+#[test]
+fn prefix_last_token_completes_the_last_word() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let result = index.search_with_options("lunar ne", prefix_opts()).unwrap();
+    assert_eq!(hit_ids(&result), ["1"]);
+    assert!(result.get_term_stats().contains_key("new"));
+
+    // Without the option "ne" is an unknown term and ignored
+    let opts = SearchOptions{ return_term_stats: true, ..SearchOptions::default() };
+    let result = index.search_with_options("lunar ne", opts).unwrap();
+    assert_eq!(hit_ids(&result), ["1"]);
+    assert!(!result.get_term_stats().contains_key("new"));
+
+    let result = index.search_with_options("ne", prefix_opts()).unwrap();
+    assert_eq!(hit_ids(&result), ["5", "1", "2"]);
+}
+
+#[test]
+fn prefix_last_token_ignores_a_trailing_separator() {
+    let (_dir, mut index) = lunar_fixture().load();
+    assert_eq!(index.search_with_options("the ", prefix_opts()).unwrap().len(), 0);
+
+    let result = index.search_with_options("solar ", prefix_opts()).unwrap();
+    assert_eq!(hit_ids(&result), ["6"]);
+    assert_eq!(result.get_term_stats().keys().collect::<Vec<_>>(), ["solar"]);
+}
+
+#[test]
+fn prefix_last_token_caps_the_completions() {
+    let mut fixture = Fixture::new(2);
+    let bodies: Vec<String> = (0..MAX_PREFIX_COMPLETIONS + 10).map(|i| format!("term{:03}", i)).collect();
+    for (i, body) in bodies.iter().enumerate() {
+        fixture = fixture.document(&i.to_string(), body);
+    }
+    let (_dir, mut index) = fixture.load();
+
+    let result = index.search_with_options("term", prefix_opts()).unwrap();
+    assert_eq!(result.len(), MAX_PREFIX_COMPLETIONS);
+    assert_eq!(result.get_term_stats().len(), MAX_PREFIX_COMPLETIONS);
+}
+
+#[test]
+fn index_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Index>();
+}
+
+#[test]
+fn intersection_strategies_agree() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let queries = ["year moon", "lunar moon", "solar moon year", "lunar moon new", "new year", "moon", "unknown year", "unknown"];
+
+    for query in &queries {
+        let filter = index.search_with_options(query, SearchOptions{ intersection: Intersection::Filter, ..SearchOptions::default() }).unwrap();
+        let merge = index.search_with_options(query, SearchOptions{ intersection: Intersection::SortedMerge, ..SearchOptions::default() }).unwrap();
+        assert_eq!(hit_ids(&filter), hit_ids(&merge), "{}", query);
+        assert_eq!(hit_scores(&filter), hit_scores(&merge), "{}", query);
+    }
+
+    let result = index.search("year moon").unwrap();
+    assert_eq!(hit_ids(&result), ["4"]);
+    assert_eq!(index.search("solar moon year").unwrap().len(), 0);
+}
+
+#[test]
+fn term_stats_match_the_scoring() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let opts = SearchOptions{ return_term_stats: true, ..SearchOptions::default() };
+    let result = index.search_with_options("moon", opts).unwrap();
+
+    let term_stat = &result.get_term_stats()["moon"];
+    let posting_list = index.term_stat("moon").unwrap().unwrap();
+    assert_eq!(term_stat.get_document_frequency(), posting_list.len());
+    assert_eq!(term_stat.get_inverse_document_frequency(), (6.0f64 / 3.0).log10());
+    // Every match has "moon" once, so it scores exactly its IDF
+    assert!(hit_scores(&result).iter().all(|score| *score == term_stat.get_inverse_document_frequency()));
+}
+
+fn tie_breaker_fixture() -> Fixture {
+    Fixture::new(4)
+        .document_with_fields("a", &[("body", "moon"), ("year", "2001")])
+        .document_with_fields("b", &[("body", "sun"), ("year", "2001")])
+        .document_with_fields("c", &[("body", "moon"), ("year", "1999")])
+        .document_with_fields("d", &[("body", "moon moon"), ("year", "1000")])
+        .document_with_fields("e", &[("body", "moon"), ("year", "2010")])
+}
+
+fn ranked_ids(hits: &LazyHits) -> Vec<String> {
+    hits.get_ranked().iter().map(|(document_id, _)| document_id.clone()).collect()
+}
+
+#[test]
+fn tie_breaker_field_orders_equal_scores() {
+    let (_dir, mut index) = tie_breaker_fixture().load();
+
+    let opts = SearchOptions{ tie_breaker_field: Some(("year".to_string(), Order::Ascending)), ..SearchOptions::default() };
+    assert_eq!(hit_ids(&index.search_with_options("moon", opts).unwrap()), ["d", "c", "a", "e"]);
+
+    let opts = SearchOptions{ tie_breaker_field: Some(("year".to_string(), Order::Descending)), ..SearchOptions::default() };
+    assert_eq!(hit_ids(&index.search_with_options("moon", opts).unwrap()), ["d", "e", "a", "c"]);
+}
+
+#[test]
+fn tie_breaker_field_only_loads_tied_documents() {
+    let (_dir, mut index) = tie_breaker_fixture().load();
+    let opts = SearchOptions{ tie_breaker_field: Some(("year".to_string(), Order::Ascending)), ..SearchOptions::default() };
+    let hits = index.search_lazy("moon", opts).unwrap();
+    assert_eq!(ranked_ids(&hits), ["d", "c", "a", "e"]);
+    drop(hits);
+
+    let tied_shards: BTreeSet<usize> = ["a", "c", "e"].iter().map(|document_id| calculate_shard_id(document_id, 4) as usize).collect();
+    assert!(!tied_shards.contains(&(calculate_shard_id("d", 4) as usize)));
+    assert_eq!(index.loaded_documents_shards.keys().copied().collect::<BTreeSet<_>>(), tied_shards);
+}
+
+#[test]
+fn tie_breaker_field_treats_a_missing_document_as_missing_the_field() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = tie_breaker_fixture().write(dir.path());
+    // "z" has stats but no source
+    let shard_id = calculate_shard_id("z", 4);
+    let term_stats = fs::read_to_string(format!("{}/{}/tst", index_name, calculate_shard_id("moon", 4))).unwrap();
+    fs::write(format!("{}/{}/tst", index_name, calculate_shard_id("moon", 4)), term_stats.replace("moon,a c d e", "moon,a c d e z")).unwrap();
+    let mut document_stats = fs::OpenOptions::new().append(true).open(format!("{}/{}/dst", index_name, shard_id)).unwrap();
+    writeln!(document_stats, "z,moon:1,").unwrap();
+
+    let mut index = Index::load(&index_name).unwrap();
+    let opts = SearchOptions{ tie_breaker_field: Some(("year".to_string(), Order::Ascending)), ..SearchOptions::default() };
+    let hits = index.search_lazy("moon", opts).unwrap();
+    assert_eq!(ranked_ids(&hits), ["d", "c", "a", "e", "z"]);
+}
+
+fn loaded_bytes(index: &Index) -> usize {
+    index.loaded_documents_shards.values().chain(index.loaded_document_stats_shards.values()).chain(index.loaded_term_stats_shards.values()).sum()
+}
+
+#[test]
+fn loaded_shards_count_their_file_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().write(dir.path());
+    let mut index = Index::load(&index_name).unwrap();
+    index.warm().unwrap();
+
+    for shard_id in 0..3 {
+        let file_size = |extension: &str| fs::metadata(format!("{}/{}/{}", index_name, shard_id, extension)).unwrap().len() as usize;
+        assert_eq!(index.loaded_documents_shards[&shard_id], file_size("dcs"));
+        assert_eq!(index.loaded_document_stats_shards[&shard_id], file_size("dst"));
+        assert_eq!(index.loaded_term_stats_shards[&shard_id], file_size("tst"));
+    }
+}
+
+#[test]
+fn max_loaded_bytes_evicts_on_every_load_path() {
+    let (_dir, mut unbounded) = lunar_fixture().load();
+    let (_dir, mut index) = lunar_fixture().load();
+    let max_loaded_bytes = 200;
+    index.set_max_loaded_bytes(Some(max_loaded_bytes));
+
+    for query in &["lunar new year", "moon", "new year", "solar eclipse", "lunar new year"] {
+        let expected = serde_json::to_string(&unbounded.search(query).unwrap().hits).unwrap();
+        assert_eq!(serde_json::to_string(&index.search(query).unwrap().hits).unwrap(), expected);
+        assert!(loaded_bytes(&index) <= max_loaded_bytes);
+
+        let lazy: Vec<Hit> = index.search_lazy(query, SearchOptions::default()).unwrap().map(Result::unwrap).collect();
+        assert_eq!(serde_json::to_string(&lazy).unwrap(), expected);
+        assert!(loaded_bytes(&index) <= max_loaded_bytes);
+
+        index.search_stream(query, SearchOptions::default(), io::sink()).unwrap();
+        assert!(loaded_bytes(&index) <= max_loaded_bytes);
+
+        assert_eq!(index.count_by_shard(query).unwrap(), unbounded.count_by_shard(query).unwrap());
+        assert!(loaded_bytes(&index) <= max_loaded_bytes);
+    }
+
+    assert_eq!(index.terms_with_prefix("n", 10).unwrap(), ["new"]);
+    assert!(loaded_bytes(&index) <= max_loaded_bytes);
+    assert_eq!(index.fetch_documents(&["1", "2", "3"]).unwrap(), unbounded.fetch_documents(&["1", "2", "3"]).unwrap());
+    assert!(loaded_bytes(&index) <= max_loaded_bytes);
+    assert_eq!(index.term_stat("moon").unwrap(), unbounded.term_stat("moon").unwrap());
+    assert!(loaded_bytes(&index) <= max_loaded_bytes);
+    index.warm().unwrap();
+    assert!(loaded_bytes(&index) <= max_loaded_bytes);
+}
+
+// Records the CRC32 of every shard file in the index's checksums file
+fn write_checksums(index_name: &str, shard_count: u32) {
+    let mut checksums = String::from("shard,ext,checksum\n");
+    for shard_id in 0..shard_count {
+        for extension in &["dcs", "dst", "tst"] {
+            let bytes = fs::read(format!("{}/{}/{}", index_name, shard_id, extension)).unwrap();
+            checksums.push_str(&format!("{},{},{:08x}\n", shard_id, extension, crc32fast::hash(&bytes)));
+        }
+    }
+    fs::write(format!("{}/checksums", index_name), checksums).unwrap();
+}
+
+#[test]
+fn checksums_pass_for_intact_shards() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().write(dir.path());
+    write_checksums(&index_name, 3);
+
+    let mut index = Index::load(&index_name).unwrap();
+    assert_eq!(index.source.checksums.len(), 9);
+    index.warm().unwrap();
+    assert_eq!(hit_ids(&index.search("lunar").unwrap()), ["1"]);
+}
+
+#[test]
+fn checksums_catch_a_flipped_byte() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().write(dir.path());
+    write_checksums(&index_name, 3);
+
+    let path = format!("{}/{}/tst", index_name, calculate_shard_id("lunar", 3));
+    let mut bytes = fs::read(&path).unwrap();
+    let last = bytes.len() - 2;
+    bytes[last] ^= 0x01;
+    fs::write(&path, bytes).unwrap();
+
+    let mut index = Index::load(&index_name).unwrap();
+    assert!(matches!(index.search("lunar"), Err(IndexError::ChecksumMismatch { ref ext, .. }) if ext == "tst"));
+
+    index.set_verify_checksums(false);
+    assert!(index.search("lunar").is_ok());
+}
+
+#[test]
+fn checksums_file_must_parse() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().write(dir.path());
+    for checksums in &["shard,ext,checksum\nzero,dcs,00000000\n", "shard,ext,checksum\n0,dcs,nothex\n", "shard,ext,checksum\n0,dcs\n"] {
+        fs::write(format!("{}/checksums", index_name), checksums).unwrap();
+        assert!(matches!(Index::load(&index_name), Err(IndexError::InvalidFile(_))), "{}", checksums);
+    }
+}
+
+// Serves the files under `dir` from a local HTTP server, returning its URL
+#[cfg(feature = "http")]
+fn serve_dir(dir: &Path) -> String {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", server.server_addr());
+    let dir = dir.to_path_buf();
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = match fs::read(dir.join(request.url().trim_start_matches('/'))) {
+                Ok(bytes) => tiny_http::Response::from_data(bytes),
+                Err(_) => tiny_http::Response::from_data(Vec::new()).with_status_code(404),
+            };
+            request.respond(response).unwrap();
+        }
+    });
+    url
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn http_storage_loads_an_index_from_a_url() {
+    let dir = tempfile::tempdir().unwrap();
+    lunar_fixture().write(dir.path());
+    let url = serve_dir(dir.path());
+
+    let mut index = Index::load(&format!("{}/index", url)).unwrap();
+    assert_eq!(index.get_shard_count(), 3);
+    let (_dir, mut local) = lunar_fixture().load();
+    for query in &["lunar new year", "moon"] {
+        let expected = serde_json::to_string(&local.search(query).unwrap().hits).unwrap();
+        assert_eq!(serde_json::to_string(&index.search(query).unwrap().hits).unwrap(), expected);
+    }
+
+    assert!(matches!(Index::load(&format!("{}/missing", url)), Err(IndexError::FileNotFound(_))));
+}
+
+#[test]
+fn embedded_storage_serves_listed_files() {
+    static FILES: &[(&str, &[u8])] = &[
+        ("index/shard_count", include_bytes!("fixtures/index/shard_count")),
+        ("index/0/dcs", include_bytes!("fixtures/index/0/dcs")),
+        ("index/0/dst", include_bytes!("fixtures/index/0/dst")),
+        ("index/0/tst", include_bytes!("fixtures/index/0/tst")),
+        ("index/1/dcs", include_bytes!("fixtures/index/1/dcs")),
+        ("index/1/dst", include_bytes!("fixtures/index/1/dst")),
+        ("index/1/tst", include_bytes!("fixtures/index/1/tst")),
+    ];
+
+    let mut index = Index::load_with_storage("index", EmbeddedStorage::new(FILES)).unwrap();
+    assert_eq!(hit_ids(&index.search("lunar eclipse").unwrap()), ["5"]);
+}
+
+#[cfg(feature = "include_dir")]
+#[test]
+fn embedded_storage_serves_an_included_dir() {
+    static FIXTURES: include_dir::Dir = include_dir::include_dir!("$CARGO_MANIFEST_DIR/src/fixtures");
+
+    let mut index = Index::load_with_storage("index", EmbeddedStorage::from_dir(&FIXTURES)).unwrap();
+    assert_eq!(index.get_shard_count(), 2);
+    assert_eq!(hit_ids(&index.search("lunar eclipse").unwrap()), ["5"]);
+    assert!(matches!(Index::load_with_storage("missing", EmbeddedStorage::from_dir(&FIXTURES)), Err(IndexError::FileNotFound(_))));
+}
+
+// The result as JSON without its timings, which differ between runs
+fn result_json(result: &SearchResult) -> String {
+    let mut result = serde_json::to_value(result).unwrap();
+    result.as_object_mut().unwrap().remove("time");
+    serde_json::to_string(&result).unwrap()
+}
+
+const VOCABULARY: &[&str] = &["lunar", "new", "year", "moon", "sun", "harvest", "eclipse", "the", "festival"];
+
+proptest::proptest! {
+    #![proptest_config(proptest::test_runner::Config::with_cases(64))]
+
+    #[test]
+    fn identical_searches_return_identical_results(
+        shard_count in 1u32..5,
+        documents in proptest::collection::vec(proptest::collection::vec(proptest::sample::select(VOCABULARY), 1..6), 1..20),
+        query in proptest::collection::vec(proptest::sample::select(VOCABULARY), 1..4),
+    ) {
+        let mut fixture = Fixture::new(shard_count);
+        for (i, words) in documents.iter().enumerate() {
+            fixture = fixture.document(&format!("doc{}", i), &words.join(" "));
+        }
+        let (_dir, mut index) = fixture.load();
+        let query = query.join(" ");
+
+        // The first search loads the shards, the second runs on loaded ones
+        let first = result_json(&index.search(&query).unwrap());
+        let second = result_json(&index.search(&query).unwrap());
+        proptest::prop_assert_eq!(&first, &second);
+
+        let (_dir, mut other) = fixture.load();
+        other.warm().unwrap();
+        proptest::prop_assert_eq!(&first, &result_json(&other.search(&query).unwrap()));
+    }
+}
+
+const GOLDEN_QUERIES: &[&str] = &["lunar", "lunar new year", "moon", "full moon", "harvest moon", "eclipse", "sun earth", "new", "the year", "mooncakes", "nothing"];
+
+// A JSON object of each query's hits as `[id, score]`, one query per line so
+// changes diff well. Scores are rounded so that harmless floating point
+// noise doesn't fail the comparison.
+fn golden_results(index: &mut Index, queries: &[&str]) -> String {
+    let mut lines = Vec::new();
+    for query in queries {
+        let result = index.search(query).unwrap();
+        let hits: Vec<(&str, f64)> = result.hits.iter().map(|hit| (hit.id.as_str(), (hit.score * 10_000.0).round() / 10_000.0)).collect();
+        lines.push(format!("  {}: {}", serde_json::to_string(query).unwrap(), serde_json::to_string(&hits).unwrap()));
+    }
+    format!("{{\n{}\n}}\n", lines.join(",\n"))
+}
+
+// Run with UPDATE_GOLDEN=1 to rewrite the golden file after an intended
+// change to scoring
+#[test]
+fn search_results_match_the_golden_file() {
+    let golden_path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/fixtures/golden.json");
+    let mut index = Index::load(concat!(env!("CARGO_MANIFEST_DIR"), "/src/fixtures/index")).unwrap();
+    let results = golden_results(&mut index, GOLDEN_QUERIES);
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(golden_path, &results).unwrap();
+    }
+    assert_eq!(results, fs::read_to_string(golden_path).unwrap());
+}
+
+#[test]
+fn preserving_ascii_folding_indexes_both_forms_after_reindex() {
+    let (_dir, mut index) = Fixture::new(2)
+        .document("1", "Café au lait")
+        .document("2", "Cafe noir")
+        .document("3", "Thé vert")
+        .load();
+    *index.get_analyzer_mut() = Analyzer::with_filters(vec![Box::new(LowercaseFilter), Box::new(PunctuationFilter), Box::new(PreservingAsciiFoldingFilter), Box::new(StopWordFilter)]);
+    assert_eq!(index.get_analyzer_mut().analyze("Café"), ["café", "cafe"]);
+
+    // The shards only hold the form each document was written with
+    assert_eq!(index.search("café").unwrap().len(), 0);
+
+    index.reindex().unwrap();
+    assert_eq!(index.term_stat("café").unwrap().unwrap(), ["1"]);
+    assert_eq!(index.term_stat("cafe").unwrap().unwrap(), ["1", "2"]);
+    let exact = index.search("café").unwrap();
+    let folded = index.search("cafe").unwrap();
+    assert_eq!(hit_ids(&exact), ["1"]);
+    assert_eq!(hit_ids(&folded), ["1", "2"]);
+    // The exact form also scores the rarer accented term
+    assert!(exact.hits[0].score > folded.hits[0].score, "{} {}", exact.hits[0].score, folded.hits[0].score);
+}
+
+#[test]
+fn identifier_split_filter_splits_camel_and_snake_case() {
+    let analyzer = Analyzer::with_filters(vec![Box::new(IdentifierSplitFilter), Box::new(LowercaseFilter)]);
+    assert_eq!(analyzer.analyze("getUserName"), ["get", "user", "name", "getusername"]);
+    assert_eq!(analyzer.analyze("max_token_length"), ["max", "token", "length", "max_token_length"]);
+    assert_eq!(analyzer.analyze("HTTPServer"), ["http", "server", "httpserver"]);
+    assert_eq!(analyzer.analyze("plain"), ["plain"]);
+}
+
+#[test]
+fn identifier_split_filter_matches_parts_after_reindex() {
+    let (_dir, mut index) = Fixture::new(2)
+        .document("1", "getUserName")
+        .document("2", "user profile")
+        .load();
+    *index.get_analyzer_mut() = Analyzer::with_filters(vec![Box::new(IdentifierSplitFilter), Box::new(LowercaseFilter), Box::new(PunctuationFilter), Box::new(StopWordFilter)]);
+
+    // "user" is a term of document 2 only, which rules document 1 out
+    assert_eq!(index.search("getUserName").unwrap().len(), 0);
+
+    index.reindex().unwrap();
+    assert_eq!(hit_ids(&index.search("getUserName").unwrap()), ["1"]);
+    assert_eq!(hit_ids(&index.search("user").unwrap()), ["1", "2"]);
+}
+
+// Every file of the index written under `dir`, keyed by its path relative to it
+#[cfg(any(feature = "zip", feature = "tar"))]
+fn index_files(dir: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut files = Vec::new();
+    let mut directories = vec![dir.join("index")];
+    while let Some(directory) = directories.pop() {
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                directories.push(path);
+            } else {
+                let name = path.strip_prefix(dir).unwrap().to_str().unwrap().to_string();
+                files.push((name, fs::read(&path).unwrap()));
+            }
+        }
+    }
+    files
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn archive_storage_searches_a_zip_without_extracting() {
+    let dir = tempfile::tempdir().unwrap();
+    lunar_fixture().write(dir.path());
+
+    let archive_path = dir.path().join("index.zip");
+    let mut zip = zip::ZipWriter::new(fs::File::create(&archive_path).unwrap());
+    for (name, bytes) in index_files(dir.path()) {
+        zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+        zip.write_all(&bytes).unwrap();
+    }
+    zip.finish().unwrap();
+    // Only the archive is left to read from
+    fs::remove_dir_all(dir.path().join("index")).unwrap();
+
+    let storage = ArchiveStorage::open(archive_path.to_str().unwrap()).unwrap();
+    let mut index = Index::load_with_storage("index", storage).unwrap();
+    let (_dir, mut local) = lunar_fixture().load();
+    assert_eq!(result_json(&index.search("new year").unwrap()), result_json(&local.search("new year").unwrap()));
+}
+
+#[cfg(feature = "tar")]
+#[test]
+fn archive_storage_searches_a_tar_without_extracting() {
+    let dir = tempfile::tempdir().unwrap();
+    lunar_fixture().write(dir.path());
+
+    let mut tar = tar::Builder::new(Vec::new());
+    for (name, bytes) in index_files(dir.path()) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, name, bytes.as_slice()).unwrap();
+    }
+    let archive = tar.into_inner().unwrap();
+
+    let mut index = Index::load_with_storage("index", ArchiveStorage::from_tar(archive.as_slice()).unwrap()).unwrap();
+    assert_eq!(hit_ids(&index.search("new year").unwrap()), ["5", "1"]);
+}
+
+#[test]
+fn reordered_filters_fold_before_removing_stop_words() {
+    let mut analyzer = Analyzer::with_filters(vec![
+        Box::new(LowercaseFilter),
+        Box::new(StopWordFilter),
+        Box::new(AsciiFoldingFilter),
+    ]);
+    // Folded after stop word removal, "thé" is no longer a stop word when checked
+    assert_eq!(analyzer.analyze("Thé room"), ["the", "room"]);
+
+    analyzer.get_filters_mut().swap(1, 2);
+    assert_eq!(analyzer.analyze("Thé room"), ["room"]);
+}
+
+#[test]
+fn single_token_query_orders_by_score_then_id() {
+    let fixture = Fixture::new(3)
+        .document("c", "Moon")
+        .document("a", "Moon")
+        .document("b", "Moon over the moon")
+        .document("d", "Sun");
+    let (_dir, mut index) = fixture.load();
+    let result = index.search("moon").unwrap();
+    assert_eq!(hit_ids(&result), ["b", "a", "c"]);
+    for pair in result.hits.windows(2) {
+        assert!(pair[0].score > pair[1].score || (pair[0].score == pair[1].score && pair[0].id < pair[1].id));
+    }
+
+    for _ in 0..10 {
+        let (_dir, mut index) = fixture.load();
+        assert_eq!(result_json(&index.search("moon").unwrap()), result_json(&result));
+    }
+}
+
+#[test]
+fn boosted_document_outranks_an_equally_relevant_one() {
+    let fixture = Fixture::new(3)
+        .document("a", "Lunar eclipse")
+        .document("b", "Lunar eclipse").boost(2.0)
+        .document("c", "Solar eclipse");
+    let (_dir, mut index) = fixture.load();
+    let result = index.search("lunar").unwrap();
+    assert_eq!(hit_ids(&result), ["b", "a"]);
+    assert_eq!(result.hits[0].score, result.hits[1].score * 2.0);
+}
+
+#[test]
+fn boost_is_read_back_from_the_document_stats() {
+    let fixture = Fixture::new(3)
+        .document("a", "Lunar eclipse").boost(2.0)
+        .document("b", "Lunar eclipse");
+    let (_dir, mut index) = fixture.load();
+    index.search("lunar").unwrap();
+    assert_eq!(index.document_stats["a"].get_boost(), 2.0);
+    // A row without a boost keeps the default
+    assert_eq!(index.document_stats["b"].get_boost(), 1.0);
+}
+
+#[test]
+fn search_on_an_unloaded_index_is_not_loaded() {
+    let mut index = Index::new();
+    assert!(matches!(index.search("moon"), Err(IndexError::NotLoaded)));
+    assert!(matches!(index.search_with_options("moon", SearchOptions::default()), Err(IndexError::NotLoaded)));
+}
+
+#[test]
+fn term_stat_document_ids_are_sorted_and_deduplicated() {
+    let mut term_stats = TermStats::new();
+    let tst = "term,ids\nmoon,c a c\nsun,b\nmoon,b a\n";
+    load_term_stats_from_reader(&mut term_stats, tst.as_bytes(), IdNormalization::default()).unwrap();
+
+    let document_ids: Vec<&str> = term_stats["moon"].get_document_id_set().iter().map(String::as_str).collect();
+    assert_eq!(document_ids, ["a", "b", "c"]);
+    assert_eq!(find_documents(&term_stats, &["moon"]).unwrap(), ["a", "b", "c"]);
+    assert_eq!(find_documents(&term_stats, &["sun", "moon"]).unwrap(), ["b"]);
+}
+
+#[test]
+#[allow(deprecated)]
+fn deprecated_document_id_accessors_still_work() {
+    let mut term_stat = TermStat::new();
+    term_stat.get_document_id_set_mut().extend(["b".to_string(), "a".to_string()]);
+    assert_eq!(*term_stat.get_document_ids(), ["a", "b"]);
+
+    term_stat.get_document_ids_mut().push("a".to_string());
+    term_stat.get_document_ids_mut().insert(0, "c".to_string());
+    assert_eq!(*term_stat.get_document_ids(), ["a", "b", "c"]);
+    assert_eq!(term_stat.get_document_id_set().len(), 3);
+}
+
+fn numeric_analyzer() -> Analyzer {
+    let mut analyzer = Analyzer::new();
+    analyzer.set_numeric_tokens(true);
+    analyzer
+}
+
+#[test]
+fn numeric_tokens_keep_formatted_numbers_whole() {
+    assert_eq!(Analyzer::new().analyze("1,000 2024 3.14"), ["1", "000", "2024", "314"]);
+    assert_eq!(numeric_analyzer().analyze("1,000 2024 3.14"), ["1000", "2024", "3.14"]);
+    // Only a group of exactly three digits follows a thousands separator
+    assert_eq!(numeric_analyzer().analyze("1,00 1,0000"), ["1", "00", "1", "0000"]);
+}
+
+#[test]
+fn numeric_tokens_match_at_index_and_query_time() {
+    let fixture = Fixture::new(3)
+        .document("a", "Sold 1,000 units in 2024")
+        .document("b", "Pi is roughly 3.14")
+        .document("c", "Sold 314 units")
+        .analyzer(numeric_analyzer());
+    let (_dir, mut index) = fixture.load();
+    index.get_analyzer_mut().set_numeric_tokens(true);
+
+    assert_eq!(hit_ids(&index.search("1,000").unwrap()), ["a"]);
+    assert_eq!(hit_ids(&index.search("1000").unwrap()), ["a"]);
+    assert_eq!(hit_ids(&index.search("2024").unwrap()), ["a"]);
+    assert_eq!(hit_ids(&index.search("3.14").unwrap()), ["b"]);
+    assert_eq!(hit_ids(&index.search("314").unwrap()), ["c"]);
+}
+
+#[test]
+fn flatten_source_returns_dotted_keys() {
+    let fixture = Fixture::new(3).document_with_fields("1", &[("user.name", "Ada"), ("body", "Lunar notes")]);
+    let (_dir, mut index) = fixture.load();
+
+    let result = index.search("ada").unwrap();
+    assert_eq!(result.hits[0].source, serde_json::json!({"id": "1", "user": {"name": "Ada"}, "body": "Lunar notes"}));
+
+    let opts = SearchOptions{ flatten_source: true, ..SearchOptions::default() };
+    let result = index.search_with_options("ada", opts).unwrap();
+    assert_eq!(result.hits[0].source, serde_json::json!({"id": "1", "user.name": "Ada", "body": "Lunar notes"}));
+
+    let opts = SearchOptions{ flatten_source: true, ..SearchOptions::default() };
+    let hits: Vec<Hit> = index.search_lazy("ada", opts).unwrap().map(Result::unwrap).collect();
+    assert_eq!(hits[0].source["user.name"], "Ada");
+}
+
+#[test]
+fn top_score_ratio_drops_hits_below_the_fraction() {
+    let fixture = Fixture::new(3)
+        .document("a", "moon moon moon moon")
+        .document("b", "moon moon")
+        .document("c", "moon")
+        .document("d", "sun");
+    let (_dir, mut index) = fixture.load();
+    let scores = hit_scores(&index.search("moon").unwrap());
+    assert!(scores[2] < scores[0] * 0.5 && scores[1] >= scores[0] * 0.5, "{:?}", scores);
+
+    let opts = SearchOptions{ top_score_ratio: Some(0.5), ..SearchOptions::default() };
+    let result = index.search_with_options("moon", opts).unwrap();
+    assert_eq!(hit_ids(&result), ["a", "b"]);
+    assert_eq!(result.len(), 2);
+}
+
+// Reads from the filesystem and counts the files opened, the counts are
+// shared with the clones kept by the test
+#[derive(Clone, Default)]
+struct CountingStorage {
+    opened: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl CountingStorage {
+    // Number of files opened whose path ends with `suffix`
+    fn opened(&self, suffix: &str) -> usize {
+        self.opened.lock().unwrap().iter().filter(|path| path.ends_with(suffix)).count()
+    }
+}
+
+impl Storage for CountingStorage {
+    fn open(&self, path: &str) -> Result<Box<dyn io::Read + '_>, Error> {
+        self.opened.lock().unwrap().push(path.to_string());
+        FileStorage.open(path)
+    }
+}
+
+#[test]
+fn lazy_hits_fetch_sources_when_consumed() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().write(dir.path());
+    let storage = CountingStorage::default();
+    let mut index = Index::load_with_storage(&index_name, storage.clone()).unwrap();
+
+    let mut hits = index.search_lazy("moon", SearchOptions::default()).unwrap();
+    assert_eq!(ranked_ids(&hits), ["2", "3", "4"]);
+    assert_eq!(storage.opened("/dcs"), 0);
+
+    // Every document of the fixture is in the same shard
+    assert_eq!(hits.next().unwrap().unwrap().id, "2");
+    assert_eq!(storage.opened("/dcs"), 1);
+    assert_eq!(hits.next().unwrap().unwrap().id, "3");
+    assert_eq!(hits.next().unwrap().unwrap().id, "4");
+    assert_eq!(storage.opened("/dcs"), 1);
+    assert!(hits.next().is_none());
+}
+
+// Documents a and c are in shard 0 and b in shard 1, which also holds a
+// second copy of a with "lunar" three times
+fn duplicate_fixture(dir: &Path) -> String {
+    let index_name = Fixture::new(2)
+        .document("a", "Lunar eclipse")
+        .document("b", "Solar eclipse")
+        .document("c", "Lunar phases")
+        .write(dir);
+    let append = |shard_id: u32, ext: &str, row: &str| {
+        let path = format!("{}/{}/{}", index_name, shard_id, ext);
+        fs::write(&path, fs::read_to_string(&path).unwrap() + row).unwrap();
+    };
+    // "a" and "c" live in shard 0, "b" in shard 1
+    append(1, "dcs", "a,Lunar lunar lunar eclipse\n");
+    append(1, "dst", "a,eclipse:1 lunar:3,\n");
+    append(0, "dcs", "c,Lunar lunar phases\n");
+    append(0, "dst", "c,lunar:2 phases:1,\n");
+    index_name
+}
+
+#[test]
+fn duplicate_policy_decides_which_copy_in_a_shard_scores() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = duplicate_fixture(dir.path());
+    // Three distinct documents, two of them with "lunar"
+    let idf = (3.0f64 / 2.0).log10();
+
+    let mut index = Index::load(&index_name).unwrap();
+    let result = index.search("lunar").unwrap();
+    assert_eq!(hit_ids(&result), ["c", "a"]);
+    assert_eq!(hit_scores(&result), [2.0 * idf, idf]);
+    assert_eq!(result.hits[0].source["body"], "Lunar lunar phases");
+
+    let mut index = Index::load(&index_name).unwrap();
+    index.set_duplicate_policy(DuplicatePolicy::KeepFirst);
+    let result = index.search("lunar").unwrap();
+    assert_eq!(hit_scores(&result), [idf, idf]);
+    assert_eq!(result.hits[1].source["body"], "Lunar phases");
+
+    let mut index = Index::load(&index_name).unwrap();
+    index.set_duplicate_policy(DuplicatePolicy::Error);
+    assert!(matches!(index.search("lunar"), Err(IndexError::DuplicateDocument(id)) if id == "c"));
+}
+
+#[test]
+fn home_shard_copy_wins_whatever_the_load_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = duplicate_fixture(dir.path());
+    let idf = (3.0f64 / 2.0).log10();
+
+    // Only shard 1, with the copy of "a" that doesn't belong there, is loaded
+    let mut index = Index::load(&index_name).unwrap();
+    assert_eq!(hit_ids(&index.search("solar").unwrap()), ["b"]);
+    assert_eq!(index.loaded_document_stats_shards.keys().collect::<Vec<_>>(), [&1]);
+    assert_eq!(index.loaded_documents_shards.keys().collect::<Vec<_>>(), [&1]);
+
+    let result = index.search("lunar").unwrap();
+    assert_eq!(hit_ids(&result), ["c", "a"]);
+    assert_eq!(hit_scores(&result), [2.0 * idf, idf]);
+    assert_eq!(result.hits[1].source["body"], "Lunar eclipse");
+    assert_eq!(index.fetch_documents(&["a"]).unwrap()[0]["body"], "Lunar eclipse");
+
+    // The same once shard 0 is loaded first
+    let mut index = Index::load(&index_name).unwrap();
+    index.search("lunar").unwrap();
+    index.search("solar").unwrap();
+    assert_eq!(hit_scores(&index.search("lunar").unwrap()), [2.0 * idf, idf]);
+    assert_eq!(index.fetch_documents(&["a"]).unwrap()[0]["body"], "Lunar eclipse");
+}
+
+#[test]
+fn duplicate_ids_are_counted_once_for_idf() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut index = Index::load(&duplicate_fixture(dir.path())).unwrap();
+    let opts = SearchOptions{ return_term_stats: true, ..SearchOptions::default() };
+    let result = index.search_with_options("eclipse", opts).unwrap();
+    assert_eq!(result.get_term_stats()["eclipse"].get_inverse_document_frequency(), (3.0f64 / 2.0).log10());
+}
+
+#[test]
+fn path_template_reads_an_alternate_layout() {
+    assert_eq!(shard_path("{name}/shard-{shard}.{ext}", "index", 2, "dst"), "index/shard-2.dst");
+    assert_eq!(shard_path(DEFAULT_PATH_TEMPLATE, "index", 2, "dst"), "index/2/dst");
+
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().write(dir.path());
+    for shard_id in 0..3 {
+        for ext in ["dcs", "dst", "tst"] {
+            fs::rename(format!("{}/{}/{}", index_name, shard_id, ext), format!("{}/shard-{}.{}", index_name, shard_id, ext)).unwrap();
+        }
+        fs::remove_dir(format!("{}/{}", index_name, shard_id)).unwrap();
+    }
+
+    let storage = CountingStorage::default();
+    let mut index = Index::load_with_storage(&index_name, storage.clone()).unwrap();
+    assert!(matches!(index.search("moon"), Err(IndexError::FileNotFound(_))));
+
+    let mut index = Index::load_with_storage(&index_name, storage.clone()).unwrap();
+    index.set_path_template("{name}/shard-{shard}.{ext}");
+    assert_eq!(hit_ids(&index.search("moon").unwrap()), ["2", "3", "4"]);
+    assert!(storage.opened("/shard-0.dst") > 0);
+}
+
+#[test]
+fn search_time_parts_sum_to_the_total() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let time = index.search("new year").unwrap().time;
+    let parts = time.match_ + time.sort + time.fetch;
+    assert!(parts <= time.total);
+    // Only the bookkeeping between the measured parts is left out
+    assert!(time.total - parts < Duration::from_millis(50), "{:?}", time);
+}
+
+#[test]
+fn unicode_spaces_separate_tokens() {
+    let analyzer = Analyzer::new();
+    assert_eq!(analyzer.analyze("new\u{a0}year"), ["new", "year"]);
+    assert_eq!(analyzer.analyze("new\u{2009}year"), ["new", "year"]);
+
+    let fixture = Fixture::new(3)
+        .document("a", "Lunar\u{a0}New\u{2009}Year")
+        .document("b", "New moon");
+    let (_dir, mut index) = fixture.load();
+    assert_eq!(hit_ids(&index.search("new year").unwrap()), ["a"]);
+    assert_eq!(hit_ids(&index.search("new\u{a0}year").unwrap()), ["a"]);
+    assert_eq!(hit_ids(&index.search("lunar\u{2009}new").unwrap()), ["a"]);
+}
+
+#[test]
+fn term_stat_returns_the_term_stats_shard_row() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().write(dir.path());
+    let mut index = Index::load(&index_name).unwrap();
+
+    let shard_id = calculate_shard_id("moon", 3);
+    let mut csvr = csv::Reader::from_path(format!("{}/{}/tst", index_name, shard_id)).unwrap();
+    let row = csvr.records().map(Result::unwrap).find(|record| &record[0] == "moon").unwrap();
+    let document_ids: Vec<String> = row[1].split(' ').map(String::from).collect();
+    assert_eq!(document_ids, ["2", "3", "4"]);
+
+    assert_eq!(index.term_stat("moon").unwrap(), Some(document_ids));
+    // Terms are not analyzed
+    assert_eq!(index.term_stat("Moon").unwrap(), None);
+    assert_eq!(index.term_stat("comet").unwrap(), None);
+}
+
+#[test]
+fn max_query_tokens_limits_the_loaded_shards() {
+    let (_dir, mut index) = lunar_fixture().term_shard_count(16).load();
+    let words: Vec<String> = (0..200).map(|i| format!("word{}", i)).collect();
+    let query = format!("moon {}", words.join(" "));
+
+    let opts = SearchOptions{ max_query_tokens: Some(1), ..SearchOptions::default() };
+    let result = index.search_with_options(&query, opts).unwrap();
+    assert_eq!(hit_ids(&result), ["2", "3", "4"]);
+    assert_eq!(index.loaded_term_stats_shards.len(), 1);
+
+    let (_dir, mut index) = lunar_fixture().term_shard_count(16).load();
+    let opts = SearchOptions{ max_query_tokens: Some(1), query_token_limit: QueryTokenLimit::Reject, ..SearchOptions::default() };
+    assert!(matches!(index.search_with_options(&query, opts), Err(IndexError::TooManyQueryTokens(201))));
+    assert!(index.loaded_term_stats_shards.is_empty());
+
+    // Without a limit every term stats shard is loaded
+    index.search(&query).unwrap();
+    assert_eq!(index.loaded_term_stats_shards.len(), 16);
+}
+
+#[test]
+fn to_csv_writes_nested_and_array_fields() {
+    let fixture = Fixture::new(3)
+        .document_with_fields("1", &[("user.name", "Ada"), ("body", "Lunar notes")])
+        .document_with_fields("2", &[("user.name", "Grace, Hopper"), ("body", "Lunar log")]);
+    let (_dir, mut index) = fixture.load();
+    let mut result = index.search("lunar").unwrap();
+    // CSV shards only hold strings, arrays come from sources built elsewhere
+    result.hits[0].source["tags"] = serde_json::json!(["moon", "notes"]);
+
+    let fields = ["user.name".to_string(), "tags".to_string(), "missing".to_string()];
+    let score = result.hits[0].score;
+    let expected = format!("id,score,user.name,tags,missing\n1,{score},Ada,moon;notes,\n2,{score},\"Grace, Hopper\",,\n");
+    assert_eq!(result.to_csv(&fields).unwrap(), expected);
+}
+
+#[test]
+fn missing_document_stats_shard_leaves_its_documents_unscored() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = Fixture::new(2)
+        .document("a", "Lunar eclipse")
+        .document("b", "Lunar phases")
+        .document("c", "Solar eclipse")
+        .write(dir.path());
+    fs::remove_file(format!("{}/1/dst", index_name)).unwrap();
+
+    let mut index = Index::load(&index_name).unwrap();
+    assert!(matches!(index.search("lunar"), Err(IndexError::FileNotFound(_))));
+
+    let mut index = Index::load(&index_name).unwrap();
+    index.set_missing_document_stats_policy(MissingShardPolicy::Ignore);
+    let result = index.search("lunar").unwrap();
+    // b is still counted from its documents shard
+    assert_eq!(hit_ids(&result), ["a", "b"]);
+    assert_eq!(hit_scores(&result), [(3.0f64 / 2.0).log10(), 0.0]);
+    assert_eq!(result.hits[1].source["body"], "Lunar phases");
+}
+
+#[test]
+fn reindex_applies_a_changed_analyzer() {
+    let fixture = Fixture::new(3)
+        .document("1", "Café lunaire")
+        .document("2", "Cafe solaire");
+    let (_dir, mut index) = fixture.load();
+    assert_eq!(hit_ids(&index.search("café").unwrap()), ["1"]);
+
+    index.get_analyzer_mut().get_filters_mut().insert(1, Box::new(AsciiFoldingFilter));
+    // The query is folded but the stored terms are not, so "cafe" only
+    // matches the other document
+    assert!(hit_ids(&index.search("café lunaire").unwrap()).is_empty());
+
+    index.reindex().unwrap();
+    assert_eq!(hit_ids(&index.search("café lunaire").unwrap()), ["1"]);
+    assert_eq!(hit_ids(&index.search("café").unwrap()), ["1", "2"]);
+}
+
+#[test]
+fn max_loaded_bytes_keeps_reindexed_stats() {
+    let fixture = Fixture::new(3)
+        .document("1", "Café lunaire")
+        .document("2", "Cafe solaire")
+        .document("3", "Thé vert");
+    let (_dir, mut index) = fixture.load();
+    index.get_analyzer_mut().get_filters_mut().insert(1, Box::new(AsciiFoldingFilter));
+    index.reindex().unwrap();
+    index.set_max_loaded_bytes(Some(1));
+    let idf = (3.0f64 / 2.0).log10();
+
+    for _ in 0..2 {
+        let result = index.search("café").unwrap();
+        assert_eq!(hit_ids(&result), ["1", "2"]);
+        assert_eq!(hit_scores(&result), [idf, idf]);
+        assert_eq!(result.hits[0].source["body"], "Café lunaire");
+    }
+    assert!(index.loaded_documents_shards.is_empty());
+    assert!(!index.loaded_document_stats_shards.is_empty());
+    assert_eq!(index.term_stat("cafe").unwrap().unwrap(), ["1", "2"]);
+}
+
+#[test]
+fn term_weights_rerank_by_the_down_weighted_term() {
+    let fixture = Fixture::new(3)
+        .document("a", "moon moon moon moon moon moon eclipse")
+        .document("b", "moon eclipse eclipse")
+        .document("c", "moon")
+        .document("d", "moon")
+        .document("e", "sun");
+    let (_dir, mut index) = fixture.load();
+    assert_eq!(hit_ids(&index.search("moon eclipse").unwrap()), ["a", "b"]);
+
+    let term_weights = BTreeMap::from([("moon".to_string(), 0.1)]);
+    let opts = SearchOptions{ term_weights, ..SearchOptions::default() };
+    let result = index.search_with_options("moon eclipse", opts).unwrap();
+    assert_eq!(hit_ids(&result), ["b", "a"]);
+    let (moon_idf, eclipse_idf) = ((5.0f64 / 4.0).log10(), (5.0f64 / 2.0).log10());
+    assert_eq!(hit_scores(&result), [0.1 * moon_idf + 2.0 * eclipse_idf, 0.1 * 6.0 * moon_idf + eclipse_idf]);
+}
+
+#[test]
+fn shard_distribution_reports_every_shard() {
+    // a, c and e go to shard 2, b to shard 1, moon to shard 3 and none to 0
+    let fixture = Fixture::new(4)
+        .document("a", "Lunar eclipse")
+        .document("b", "Solar eclipse")
+        .document("c", "Harvest moon")
+        .document("e", "New moon")
+        .document("moon", "Blue moon");
+    let (_dir, index) = fixture.load();
+    let shards = index.shard_distribution().unwrap();
+
+    let document_counts: Vec<usize> = shards.iter().map(ShardInfo::get_document_count).collect();
+    assert_eq!(document_counts, [0, 1, 3, 1]);
+    assert_eq!(document_counts.iter().sum::<usize>(), 5);
+
+    let largest = shards.iter().max_by_key(|shard| shard.get_document_count()).unwrap();
+    let smallest = shards.iter().min_by_key(|shard| shard.get_document_count()).unwrap();
+    assert_eq!((largest.get_shard_id(), smallest.get_shard_id()), (2, 0));
+    assert!(largest.get_size() > smallest.get_size());
+    // Shard 0 still has the header rows of its files
+    assert!(smallest.get_size() > 0);
+}
+
+#[test]
+fn language_selects_the_stop_words() {
+    assert_eq!(Analyzer::new().analyze("The moon and le chat"), ["moon", "le", "chat"]);
+    assert_eq!(Analyzer::for_language(Language::French).analyze("The moon and le chat"), ["the", "moon", "and", "chat"]);
+    assert_eq!(Analyzer::for_language(Language::French).analyze("La lune et le soleil"), ["lune", "soleil"]);
+    assert_eq!(Analyzer::for_language(Language::German).analyze("Der Mond und die Sonne"), ["mond", "sonne"]);
+
+    let fixture = Fixture::new(3)
+        .document("1", "La lune")
+        .document("2", "Le soleil")
+        .analyzer(Analyzer::for_language(Language::French));
+    let (_dir, mut index) = fixture.load();
+    *index.get_analyzer_mut() = Analyzer::for_language(Language::French);
+    assert_eq!(hit_ids(&index.search("la lune").unwrap()), ["1"]);
+    assert_eq!(index.term_stat("la").unwrap(), None);
+}
+
+#[test]
+fn debug_analyze_reports_offsets_and_positions() {
+    let mut index = Index::new();
+    index.get_analyzer_mut().get_filters_mut().insert(1, Box::new(AsciiFoldingFilter));
+    let input = "Café, l'été! The moon";
+    let tokens = index.debug_analyze(input);
+
+    let token = |text: &str, start, end, position| AnalyzedToken{ text: text.to_string(), start, end, position };
+    assert_eq!(tokens, [
+        token("cafe", 0, 5, 0),
+        // The comma and the space each end a span, leaving an empty one between
+        token("", 6, 6, 1),
+        token("l'ete", 7, 15, 2),
+        // "The" is dropped as a stop word but keeps its position
+        token("moon", 20, 24, 4),
+    ]);
+    assert_eq!(&input[tokens[0].start..tokens[0].end], "Café");
+    assert_eq!(&input[tokens[2].start..tokens[2].end], "l'été!");
+}
+
+#[test]
+fn autocomplete_completes_and_searches_the_prefix() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let result = index.autocomplete("lun", SearchOptions::default()).unwrap();
+    assert_eq!(result.get_completions(), ["lunar"]);
+    assert_eq!(hit_ids(result.get_hits().unwrap()), ["1"]);
+
+    // Only the last word is completed and searched
+    let result = index.autocomplete("Harvest n", SearchOptions::default()).unwrap();
+    assert_eq!(result.get_completions(), ["new"]);
+    assert_eq!(hit_ids(result.get_hits().unwrap()), ["5", "1", "2"]);
+
+    let result = index.autocomplete("comet", SearchOptions::default()).unwrap();
+    assert!(result.get_completions().is_empty() && result.get_hits().is_none());
+}
+
+#[test]
+fn fetch_documents_opens_each_shard_once() {
+    let dir = tempfile::tempdir().unwrap();
+    // a and c go to shard 0, b and d to shard 1
+    let index_name = Fixture::new(2)
+        .document("a", "Lunar eclipse")
+        .document("b", "Solar eclipse")
+        .document("c", "Harvest moon")
+        .document("d", "New moon")
+        .write(dir.path());
+    let storage = CountingStorage::default();
+    let mut index = Index::load_with_storage(&index_name, storage.clone()).unwrap();
+
+    let documents = index.fetch_documents(&["d", "a", "b", "c"]).unwrap();
+    let ids: Vec<&str> = documents.iter().map(|document| document["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, ["d", "a", "b", "c"]);
+    assert_eq!(storage.opened("/0/dcs"), 1);
+    assert_eq!(storage.opened("/1/dcs"), 1);
+
+    index.fetch_documents(&["a", "b"]).unwrap();
+    assert_eq!(storage.opened("/dcs"), 2);
+}
+
+#[test]
+fn score_normalization_makes_query_lengths_comparable() {
+    let fixture = Fixture::new(3)
+        .document("a", "alpha beta gamma delta epsilon")
+        .document("b", "zeta");
+    let (_dir, mut index) = fixture.load();
+    let idf = 2.0f64.log10();
+    let score = |index: &mut Index, query: &str, score_normalization| {
+        let opts = SearchOptions{ score_normalization, ..SearchOptions::default() };
+        hit_scores(&index.search_with_options(query, opts).unwrap())[0]
+    };
+    let short_query = "alpha beta";
+    let long_query = "alpha beta gamma delta epsilon";
+
+    assert_eq!(score(&mut index, short_query, ScoreNormalization::Raw), 2.0 * idf);
+    assert_eq!(score(&mut index, long_query, ScoreNormalization::Raw), 5.0 * idf);
+    assert_eq!(score(&mut index, short_query, ScoreNormalization::QueryLength), idf);
+    assert_eq!(score(&mut index, long_query, ScoreNormalization::QueryLength), idf);
+    assert_eq!(score(&mut index, short_query, ScoreNormalization::SummedIdf), 1.0);
+    assert_eq!(score(&mut index, long_query, ScoreNormalization::SummedIdf), 1.0);
+}
+
+#[test]
+fn query_length_counts_a_completed_prefix_once() {
+    let fixture = Fixture::new(3)
+        .document("a", "alpha albedo")
+        .document("b", "alps")
+        .document("c", "zeta");
+    let (_dir, mut index) = fixture.load();
+    let score = |index: &mut Index, score_normalization| {
+        let opts = SearchOptions{ score_normalization, ..prefix_opts() };
+        hit_scores(&index.search_with_options("alpha al", opts).unwrap())[0]
+    };
+
+    // "al" completes to three terms but is still one of the two query tokens
+    let raw = score(&mut index, ScoreNormalization::Raw);
+    assert_eq!(score(&mut index, ScoreNormalization::QueryLength), raw / 2.0);
+}
+
+#[test]
+fn search_stream_writes_the_buffered_hits() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let opts = || SearchOptions{ size: 2, from: 1, ..SearchOptions::default() };
+    let result = index.search_with_options("new", opts()).unwrap();
+
+    let mut output = Vec::new();
+    let count = index.search_stream("new", opts(), &mut output).unwrap();
+    assert_eq!(count, result.len());
+
+    let lines: Vec<Value> = String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let hits: Vec<Value> = result.hits.iter().map(|hit| serde_json::to_value(hit).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines, hits);
+}
+
+#[test]
+fn analyze_document_maps_dotted_fields_to_tokens() {
+    let index = Index::new();
+    let document = serde_json::json!({
+        "title": "The Lunar New Year",
+        "user": {"name": "Ada Lovelace", "address": {"city": "London"}},
+        "tags": ["Moon", "Festival"],
+        "year": 2024,
+    });
+    let fields = index.analyze_document(&document);
+
+    let expected = |tokens: &[&str]| tokens.iter().map(|token| token.to_string()).collect::<Vec<String>>();
+    assert_eq!(fields, BTreeMap::from([
+        ("title".to_string(), expected(&["lunar", "new", "year"])),
+        ("user.name".to_string(), expected(&["ada", "lovelace"])),
+        ("user.address.city".to_string(), expected(&["london"])),
+        ("tags".to_string(), expected(&["moon", "festival"])),
+        // Only strings are analyzed
+        ("year".to_string(), expected(&[])),
+    ]));
+}
+
+#[test]
+fn term_shard_count_can_differ_from_the_document_shards() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().term_shard_count(5).write(dir.path());
+    let storage = CountingStorage::default();
+    let mut index = Index::load_with_storage(&index_name, storage.clone()).unwrap();
+    assert_eq!((index.get_shard_count(), index.get_term_shard_count()), (3, 5));
+
+    let (_dir, mut equal_counts) = lunar_fixture().load();
+    for query in ["moon", "new year", "solar eclipse"] {
+        assert_eq!(hit_ids(&index.search(query).unwrap()), hit_ids(&equal_counts.search(query).unwrap()));
+    }
+
+    // "moon" is only read from the term stats shard it hashes to among five
+    let shard_id = calculate_shard_id("moon", 5);
+    let tst = fs::read_to_string(format!("{}/{}/tst", index_name, shard_id)).unwrap();
+    assert!(tst.contains("\nmoon,2 3 4\n"));
+    assert_eq!(storage.opened(&format!("/{}/tst", shard_id)), 1);
+    // Term stats shards past the document shard count are read too
+    assert!((3..5).any(|shard_id| storage.opened(&format!("/{}/tst", shard_id)) > 0));
+}
+
+#[test]
+fn echo_query_tokens_shows_an_all_stop_word_query() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let opts = SearchOptions{ echo_query_tokens: true, ..SearchOptions::default() };
+    let result = index.search_with_options("The of a", opts).unwrap();
+    assert_eq!(result.get_query_tokens(), Some(&[][..]));
+    assert_eq!(result.len(), 0);
+
+    let opts = SearchOptions{ echo_query_tokens: true, ..SearchOptions::default() };
+    let result = index.search_with_options("The Moon", opts).unwrap();
+    assert_eq!(result.get_query_tokens(), Some(&["moon".to_string()][..]));
+    assert_eq!(index.search("The Moon").unwrap().get_query_tokens(), None);
+}
+
+#[cfg(feature = "millis")]
+#[test]
+fn search_time_serializes_milliseconds() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let result = index.search("new year").unwrap();
+    let time = serde_json::to_value(&result.time).unwrap();
+
+    assert_eq!(time["match_"].as_f64().unwrap(), result.time.match_.as_secs_f64() * 1000.0);
+    assert_eq!(time["sort"].as_f64().unwrap(), result.time.sort.as_secs_f64() * 1000.0);
+    assert_eq!(time["fetch"].as_f64().unwrap(), result.time.fetch.as_secs_f64() * 1000.0);
+    assert_eq!(time["total"].as_f64().unwrap(), result.time.total.as_secs_f64() * 1000.0);
+}
+
+#[cfg(not(feature = "millis"))]
+#[test]
+fn search_time_serializes_durations() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let result = index.search("new year").unwrap();
+    let time = serde_json::to_value(&result.time).unwrap();
+    assert_eq!(time["total"]["nanos"], result.time.total.subsec_nanos());
+}
+
+#[test]
+fn unscored_search_returns_the_scored_matches_in_id_order() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let scored = index.search("new").unwrap();
+    assert_eq!(hit_ids(&scored), ["5", "1", "2"]);
+
+    let opts = SearchOptions{ score: false, ..SearchOptions::default() };
+    let unscored = index.search_with_options("new", opts).unwrap();
+    assert_eq!(hit_ids(&unscored), ["1", "2", "5"]);
+    assert!(hit_scores(&unscored).iter().all(|score| *score == 0.0));
+    assert_eq!(unscored.len(), scored.len());
+}
+
+#[test]
+fn parallel_scoring_ranks_like_serial_scoring() {
+    let mut fixture = Fixture::new(7);
+    for i in 0..300 {
+        let body = format!("{}{}word{}", "moon ".repeat(i % 5 + 1), "eclipse ".repeat(i % 3), i);
+        fixture = fixture.document(&i.to_string(), &body);
+        if i % 4 == 0 {
+            fixture = fixture.boost(1.5);
+        }
+    }
+    let opts = |parallel_scoring| SearchOptions{ size: 300, parallel_scoring, min_document_length: Some(5), ..SearchOptions::default() };
+
+    for query in ["moon", "moon eclipse"] {
+        let (_dir, mut index) = fixture.load();
+        let serial = index.search_with_options(query, opts(false)).unwrap();
+        let (_dir, mut index) = fixture.load();
+        let parallel = index.search_with_options(query, opts(true)).unwrap();
+        assert!(serial.len() > 100);
+        assert_eq!(result_json(&parallel), result_json(&serial));
+    }
+}
+
+#[test]
+fn id_normalization_matches_padded_ids() {
+    let dir = tempfile::tempdir().unwrap();
+    // a goes to shard 0 and b to shard 1
+    let index_name = Fixture::new(2)
+        .document("a", "Lunar eclipse")
+        .document("b", "Solar eclipse")
+        .write(dir.path());
+    // The document shards hold padded and uppercased IDs, routed by their
+    // normalized form
+    for shard_id in 0..2 {
+        for ext in ["dcs", "dst"] {
+            let path = format!("{}/{}/{}", index_name, shard_id, ext);
+            let rows = fs::read_to_string(&path).unwrap().replace("\na,", "\n\" A \",").replace("\nb,", "\n b ,");
+            fs::write(&path, rows).unwrap();
+        }
+    }
+
+    let mut index = Index::load(&index_name).unwrap();
+    assert!(matches!(index.search("lunar"), Err(IndexError::DocumentNotFound(_))));
+
+    let mut index = Index::load(&index_name).unwrap();
+    index.set_id_normalization(IdNormalization::Trim);
+    assert_eq!(hit_ids(&index.search("solar").unwrap()), ["b"]);
+    assert!(matches!(index.search("lunar"), Err(IndexError::DocumentNotFound(_))));
+
+    let mut index = Index::load(&index_name).unwrap();
+    index.set_id_normalization(IdNormalization::TrimLowercase);
+    let result = index.search("eclipse").unwrap();
+    assert_eq!(hit_ids(&result), ["a", "b"]);
+    assert!(hit_scores(&result).iter().all(|score| *score == 0.0));
+    let result = index.search("lunar").unwrap();
+    assert_eq!(hit_scores(&result), [2.0f64.log10()]);
+    let documents = index.fetch_documents(&[" A", "b "]).unwrap();
+    assert_eq!((&documents[0]["body"], &documents[1]["body"]), (&Value::from("Lunar eclipse"), &Value::from("Solar eclipse")));
+}
+
+#[test]
+fn scores_match_a_per_document_recomputation() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let term_weights = BTreeMap::from([("year".to_string(), 0.5)]);
+    let opts = SearchOptions{ term_weights: term_weights.clone(), ..SearchOptions::default() };
+    let result = index.search_with_options("new year", opts).unwrap();
+    assert_eq!(hit_ids(&result), ["5", "1"]);
+
+    // Each term's IDF and weight times its frequency in the document
+    for hit in &result.hits {
+        let mut expected = 0.0;
+        for token in ["new", "year"] {
+            let document_frequency = index.term_stat(token).unwrap().unwrap().len() as f64;
+            let tf = index.document_stats[&hit.id].term_frequency[token] as f64;
+            expected += term_weights.get(token).unwrap_or(&1.0) * tf * (6.0 / document_frequency).log10();
+        }
+        assert_eq!(hit.score, expected, "{}", hit.id);
+    }
+}
+
+#[test]
+fn document_without_indexed_terms_scores_zero() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = Fixture::new(3)
+        .document_with_fields("n", &[("year", "2024")])
+        .document_with_fields("m", &[("year", "2024"), ("body", "Lunar")])
+        .document_with_fields("o", &[("body", "Solar")])
+        .write(dir.path());
+    // Written by an indexer that leaves numbers out of the document stats
+    let shard_id = calculate_shard_id("n", 3);
+    let path = format!("{}/{}/dst", index_name, shard_id);
+    fs::write(&path, fs::read_to_string(&path).unwrap().replace("\nn,2024:1,", "\nn,,")).unwrap();
+
+    let mut index = Index::load(&index_name).unwrap();
+    let opts = || SearchOptions{ min_document_length: Some(3), score_normalization: ScoreNormalization::SummedIdf, ..SearchOptions::default() };
+    let result = index.search_with_options("2024", opts()).unwrap();
+    assert_eq!(hit_ids(&result), ["m", "n"]);
+    // m is normalized to 1.0 and scaled by its two of three tokens
+    assert_eq!(hit_scores(&result), [2.0 / 3.0, 0.0]);
+    assert_eq!(index.document_stats["n"].get_length(), 0);
+    assert_eq!(result.hits[1].source["year"], "2024");
+}
+
+#[test]
+fn total_documents_override_is_used_for_scoring() {
+    let dir = tempfile::tempdir().unwrap();
+    // a goes to shard 0 and b to shard 1
+    let index_name = Fixture::new(2)
+        .document("a", "Lunar eclipse")
+        .document("b", "Solar eclipse")
+        .write(dir.path());
+    let storage = CountingStorage::default();
+    let mut index = Index::load_with_storage(&index_name, storage.clone()).unwrap();
+    index.set_total_documents(Some(100));
+    assert_eq!(hit_scores(&index.search("lunar").unwrap()), [100.0f64.log10()]);
+    // Only the matched document's stats are read, no shard is counted
+    assert_eq!(storage.opened("/1/dst"), 0);
+
+    index.set_total_documents(None);
+    assert_eq!(hit_scores(&index.search("lunar").unwrap()), [2.0f64.log10()]);
+    assert_eq!(storage.opened("/1/dst"), 1);
+}
+
+#[test]
+fn stop_word_weight_gives_kept_stop_words_a_small_score() {
+    let keep_stop_words = || Analyzer::with_filters(vec![Box::new(LowercaseFilter), Box::new(PunctuationFilter)]);
+    let fixture = Fixture::new(3)
+        .document("a", "Things to do")
+        .document("b", "Things we do")
+        .document("c", "Nothing")
+        .analyzer(keep_stop_words());
+    let (_dir, mut index) = fixture.load();
+    *index.get_analyzer_mut() = keep_stop_words();
+    let (to_idf, do_idf) = (3.0f64.log10(), 1.5f64.log10());
+
+    // The stop word still has to match
+    let opts = SearchOptions{ stop_word_weight: Some((Language::English, 0.01)), ..SearchOptions::default() };
+    let result = index.search_with_options("to do", opts).unwrap();
+    assert_eq!(hit_ids(&result), ["a"]);
+    assert_eq!(hit_scores(&result), [0.01 * to_idf + do_idf]);
+    assert!(hit_scores(&result)[0] > do_idf);
+
+    assert_eq!(hit_scores(&index.search("to do").unwrap()), [to_idf + do_idf]);
+}
+
+#[test]
+fn min_document_length_penalizes_short_documents() {
+    let fixture = Fixture::new(3)
+        .document("a", "Moon festival")
+        .document("b", "Moon rising over the harvest fields tonight")
+        .document("c", "Sun");
+    let (_dir, mut index) = fixture.load();
+    let idf = 1.5f64.log10();
+    assert_eq!(hit_scores(&index.search("moon").unwrap()), [idf, idf]);
+
+    let opts = SearchOptions{ min_document_length: Some(4), ..SearchOptions::default() };
+    let result = index.search_with_options("moon", opts).unwrap();
+    assert_eq!(hit_ids(&result), ["b", "a"]);
+    assert_eq!(hit_scores(&result), [idf, idf * 2.0 / 4.0]);
+}
+
+#[test]
+fn last_page_returns_the_available_tail() {
+    let (_dir, mut index) = lunar_fixture().load();
+    let all = index.search("moon").unwrap();
+    assert_eq!(all.len(), 3);
+
+    let opts = SearchOptions{ from: 1, size: 10, ..SearchOptions::default() };
+    let result = index.search_with_options("moon", opts).unwrap();
+    assert_eq!(hit_ids(&result), hit_ids(&all)[1..]);
+    assert_eq!(result.len(), 3);
+
+    for from in [3, 4, usize::MAX] {
+        let opts = SearchOptions{ from, size: usize::MAX, ..SearchOptions::default() };
+        assert!(index.search_with_options("moon", opts).unwrap().hits.is_empty());
+    }
+    let opts = SearchOptions{ from: 2, size: usize::MAX, ..SearchOptions::default() };
+    assert_eq!(hit_ids(&index.search_with_options("moon", opts).unwrap()), hit_ids(&all)[2..]);
+    let hits = index.search_lazy("moon", SearchOptions{ from: 2, size: 10, ..SearchOptions::default() }).unwrap();
+    assert_eq!(ranked_ids(&hits), hit_ids(&all)[2..]);
+}
+
+fn nested_hit() -> Hit {
+    let fixture = Fixture::new(3).document_with_fields("1", &[("user.name", "Ada"), ("body", "Lunar notes")]);
+    let (_dir, mut index) = fixture.load();
+    let mut result = index.search("lunar").unwrap();
+    result.hits[0].source["tags"] = serde_json::json!(["moon", "notes"]);
+    result.hits.remove(0)
+}
+
+#[test]
+fn flat_source_has_dotted_string_values() {
+    let expected = [("body", "Lunar notes"), ("id", "1"), ("tags", "moon;notes"), ("user.name", "Ada")];
+    let expected: BTreeMap<String, String> = expected.iter().map(|(field, value)| (field.to_string(), value.to_string())).collect();
+    assert_eq!(nested_hit().get_flat_source(), expected);
+}
+
+#[cfg(feature = "rmp-serde")]
+#[test]
+fn msgpack_source_round_trips() {
+    let hit = nested_hit();
+    let bytes = hit.source_to_msgpack().unwrap();
+    let source: Value = rmp_serde::from_slice(&bytes).unwrap();
+    assert_eq!(source, hit.source);
+}
+
+#[test]
+fn count_by_shard_buckets_the_matches_by_shard() {
+    let mut fixture = Fixture::new(4);
+    for i in 0..40 {
+        let body = if i % 3 == 0 { "Full moon" } else { "New moon" };
+        fixture = fixture.document(&format!("doc{}", i), body);
+    }
+    let (_dir, mut index) = fixture.load();
+
+    let counts = index.count_by_shard("new moon").unwrap();
+    let result = index.search("new moon").unwrap();
+    assert_eq!(counts.values().sum::<usize>(), result.len());
+
+    let mut expected: BTreeMap<usize, usize> = BTreeMap::new();
+    for i in (0..40).filter(|i| i % 3 != 0) {
+        *expected.entry(calculate_shard_id(&format!("doc{}", i), 4) as usize).or_insert(0) += 1;
+    }
+    assert!(expected.len() > 1);
+    assert_eq!(counts, expected);
+    assert!(index.count_by_shard("comet").unwrap().is_empty());
+}
+
+#[test]
+fn query_rewriter_runs_before_analysis() {
+    let fixture = Fixture::new(3)
+        .document("1", "New Year Eve party")
+        .document("2", "New moon")
+        .document("3", "NYE recap");
+    let (_dir, mut index) = fixture.load();
+    assert_eq!(hit_ids(&index.search("nye").unwrap()), ["3"]);
+
+    index.set_query_rewriter(|query| {
+        query.split(' ').map(|word| if word.eq_ignore_ascii_case("nye") { "new year eve" } else { word }).collect::<Vec<_>>().join(" ")
+    });
+    let opts = SearchOptions{ echo_query_tokens: true, ..SearchOptions::default() };
+    let result = index.search_with_options("NYE", opts).unwrap();
+    // The document with the raw "nye" term no longer matches
+    assert_eq!(hit_ids(&result), ["1"]);
+    assert_eq!(result.get_query_tokens().unwrap(), ["new", "year", "eve"]);
+    assert_eq!(hit_ids(&index.search("nye party").unwrap()), ["1"]);
+    assert!(hit_ids(&index.search("nye recap").unwrap()).is_empty());
+}
+
+#[test]
+fn document_count_file_may_end_with_a_newline() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().write(dir.path());
+    fs::write(format!("{}/document_count", index_name), "600\n").unwrap();
+    let mut index = Index::load(&index_name).unwrap();
+    assert_eq!(hit_scores(&index.search("lunar").unwrap()), [(600.0f64).log10()]);
+
+    fs::write(format!("{}/document_count", index_name), "six hundred").unwrap();
+    assert!(matches!(Index::load(&index_name), Err(IndexError::InvalidFile(path)) if path.ends_with("document_count")));
+}
+
+#[test]
+fn term_shard_count_file_may_end_with_a_newline() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = lunar_fixture().term_shard_count(2).write(dir.path());
+    fs::write(format!("{}/term_shard_count", index_name), "2\n").unwrap();
+    fs::write(format!("{}/shard_count", index_name), "3\n").unwrap();
+    let mut index = Index::load(&index_name).unwrap();
+    assert_eq!((index.get_shard_count(), index.get_term_shard_count()), (3, 2));
+    assert_eq!(hit_ids(&index.search("moon").unwrap()), ["2", "3", "4"]);
+
+    fs::write(format!("{}/term_shard_count", index_name), "2 shards").unwrap();
+    assert!(matches!(Index::load(&index_name), Err(IndexError::InvalidFile(path)) if path.ends_with("term_shard_count")));
+}
+
+#[test]
+fn malformed_boost_is_an_invalid_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let index_name = Fixture::new(3)
+        .document("a", "Lunar eclipse").boost(2.0)
+        .document("b", "Solar eclipse")
+        .write(dir.path());
+    let path = format!("{}/{}/dst", index_name, calculate_shard_id("a", 3));
+    fs::write(&path, fs::read_to_string(&path).unwrap().replace(",2\n", ",heavy\n")).unwrap();
+
+    let mut index = Index::load(&index_name).unwrap();
+    assert!(matches!(index.search("lunar"), Err(IndexError::InvalidFile(file_path)) if file_path == path));
+}
+
+#[test]
+fn id_normalization_recounts_the_documents() {
+    let dir = tempfile::tempdir().unwrap();
+    // a and c go to shard 0, b to shard 1
+    let index_name = Fixture::new(2)
+        .document("a", "Lunar eclipse")
+        .document("b", "Solar eclipse")
+        .document("c", "Harvest moon")
+        .write(dir.path());
+    // A second copy of a, only the same document once normalized
+    let path = format!("{}/1/dst", index_name);
+    fs::write(&path, fs::read_to_string(&path).unwrap() + "\" A \",eclipse:1 lunar:1,\n").unwrap();
+
+    let mut index = Index::load(&index_name).unwrap();
+    assert_eq!(hit_scores(&index.search("lunar").unwrap()), [4.0f64.log10()]);
+    index.set_id_normalization(IdNormalization::TrimLowercase);
+    assert_eq!(hit_scores(&index.search("lunar").unwrap()), [3.0f64.log10()]);
+}